@@ -0,0 +1,183 @@
+use cgmath::{Matrix4, Quaternion, Vector3};
+use gltf::animation::util::ReadOutputs;
+use gltf::animation::{Interpolation, Property};
+
+/// The keyframed node transforms and morph target weights of every animation in a glTF document, played together
+pub struct Animations {
+    channels: Vec<Channel>,
+}
+
+/// Keyframes of one property of one node
+struct Channel {
+    node: usize,
+    property: Property,
+    interpolation: Interpolation,
+    /// seconds, ascending
+    times: Vec<f32>,
+    /// `width` numbers per keyframe, 3 for translations and scales, 4 for rotations and one per morph target.
+    /// Cubic splines store an in-tangent, the value and an out-tangent per keyframe.
+    values: Vec<f32>,
+    width: usize,
+}
+
+impl Animations {
+    pub fn load(document: &gltf::Document, buffers: &[gltf::buffer::Data]) -> Self {
+        let mut channels = Vec::new();
+
+        for animation in document.animations() {
+            for channel in animation.channels() {
+                let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+                let (Some(inputs), Some(outputs)) = (reader.read_inputs(), reader.read_outputs()) else {
+                    continue;
+                };
+                let times: Vec<f32> = inputs.collect();
+                let values: Vec<f32> = match outputs {
+                    ReadOutputs::Translations(it) | ReadOutputs::Scales(it) => it.flatten().collect(),
+                    ReadOutputs::Rotations(it) => it.into_f32().flatten().collect(),
+                    ReadOutputs::MorphTargetWeights(it) => it.into_f32().collect(),
+                };
+                let interpolation = channel.sampler().interpolation();
+                let values_per_key = if interpolation == Interpolation::CubicSpline { 3 } else { 1 };
+                let width = values.len() / (times.len() * values_per_key).max(1);
+
+                channels.push(Channel {
+                    node: channel.target().node().index(),
+                    property: channel.target().property(),
+                    interpolation,
+                    times,
+                    values,
+                    width,
+                });
+            }
+        }
+
+        Animations { channels }
+    }
+
+    /// Local transform of `node` at `time` seconds, its static one where it is not animated
+    pub fn local_transform(&self, node: &gltf::Node, time: f32) -> Matrix4<f32> {
+        let mut channels = self
+            .channels
+            .iter()
+            .filter(|c| c.node == node.index() && c.property != Property::MorphTargetWeights)
+            .peekable();
+        if channels.peek().is_none() {
+            // keep static matrices as they are, decomposing them would lose any shear
+            return Matrix4::from(node.transform().matrix());
+        }
+        let (mut translation, mut rotation, mut scale) = node.transform().decomposed();
+
+        for channel in channels {
+            let Some(value) = channel.sample(time) else {
+                continue;
+            };
+            match (channel.property, value.as_slice()) {
+                (Property::Translation, &[x, y, z]) => translation = [x, y, z],
+                (Property::Rotation, &[x, y, z, w]) => rotation = [x, y, z, w],
+                (Property::Scale, &[x, y, z]) => scale = [x, y, z],
+                _ => {}
+            }
+        }
+
+        let [x, y, z, w] = rotation;
+        Matrix4::from_translation(Vector3::from(translation))
+            * Matrix4::from(Quaternion::new(w, x, y, z))
+            * Matrix4::from_nonuniform_scale(scale[0], scale[1], scale[2])
+    }
+}
+
+impl Animations {
+    /// Morph target weights of `node` at `time` seconds, `None` where they are not animated
+    pub fn weights(&self, node: &gltf::Node, time: f32) -> Option<Vec<f32>> {
+        self.channels
+            .iter()
+            .filter(|c| c.node == node.index() && c.property == Property::MorphTargetWeights)
+            .find_map(|channel| channel.sample(time))
+    }
+}
+
+impl Channel {
+    /// The interpolated value at `time`, held at the first and last keyframe outside of them
+    fn sample(&self, time: f32) -> Option<Vec<f32>> {
+        let cubic = self.interpolation == Interpolation::CubicSpline;
+        let width = self.width;
+        let element = |i: usize| &self.values[width * i..width * (i + 1)];
+        // cubic splines have three values per keyframe, the value itself is the middle one
+        let value = |k: usize| if cubic { element(3 * k + 1) } else { element(k) };
+        let keys = self.times.len();
+        if keys == 0 || width == 0 || self.values.len() < width * if cubic { 3 * keys } else { keys } {
+            return None;
+        }
+
+        let next = self.times.partition_point(|t| *t <= time);
+        if next == 0 {
+            return Some(value(0).to_vec());
+        }
+        if next == keys {
+            return Some(value(keys - 1).to_vec());
+        }
+        let k = next - 1;
+        let dt = self.times[next] - self.times[k];
+        let s = if dt > 0. { (time - self.times[k]) / dt } else { 0. };
+        let rotation = self.property == Property::Rotation;
+
+        let sampled = match self.interpolation {
+            Interpolation::Step => value(k).to_vec(),
+            Interpolation::Linear if rotation => slerp(value(k), value(next), s),
+            Interpolation::Linear => lerp(value(k), value(next), s),
+            Interpolation::CubicSpline => {
+                // Hermite spline, tangents are scaled by the keyframe distance (glTF 2.0 spec, Appendix C)
+                let out_tangent = element(3 * k + 2);
+                let in_tangent = element(3 * next);
+                let (s2, s3) = (s * s, s * s * s);
+                let weights = [2. * s3 - 3. * s2 + 1., dt * (s3 - 2. * s2 + s), -2. * s3 + 3. * s2, dt * (s3 - s2)];
+                let v: Vec<f32> = (0..width)
+                    .map(|i| {
+                        weights[0] * value(k)[i]
+                            + weights[1] * out_tangent[i]
+                            + weights[2] * value(next)[i]
+                            + weights[3] * in_tangent[i]
+                    })
+                    .collect();
+                if rotation {
+                    normalize(&v)
+                } else {
+                    v
+                }
+            }
+        };
+        Some(sampled)
+    }
+}
+
+fn lerp(a: &[f32], b: &[f32], s: f32) -> Vec<f32> {
+    a.iter().zip(b).map(|(a, b)| a + s * (b - a)).collect()
+}
+
+/// Spherical interpolation of unit quaternions along the shorter arc
+fn slerp(a: &[f32], b: &[f32], s: f32) -> Vec<f32> {
+    let mut cos = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    // q and -q are the same rotation, flip one so they take the short way
+    let b: Vec<f32> = if cos < 0. {
+        cos = -cos;
+        b.iter().map(|x| -x).collect()
+    } else {
+        b.to_vec()
+    };
+    // nearly the same rotation, the sine below would vanish
+    if cos > 0.9995 {
+        return normalize(&lerp(a, &b, s));
+    }
+    let angle = cos.acos();
+    let (wa, wb) = (((1. - s) * angle).sin() / angle.sin(), (s * angle).sin() / angle.sin());
+    a.iter().zip(&b).map(|(a, b)| wa * a + wb * b).collect()
+}
+
+fn normalize(q: &[f32]) -> Vec<f32> {
+    let length = q.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if length > 0. {
+        q.iter().map(|x| x / length).collect()
+    } else {
+        q.to_vec()
+    }
+}
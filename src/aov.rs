@@ -0,0 +1,284 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use exr::prelude::{AnyChannel, AnyChannels, Encoding, FlatSamples, Image, ImageAttributes, IntegerBounds, Layer, LayerAttributes, WritableImage};
+use image::{ImageError, ImageResult, Rgb32FImage};
+
+use crate::checkpoint::{invalid, read_colors, read_u64, write_colors, write_u64};
+use crate::integrator::LightPaths;
+use crate::intersections::{Intersect, IntersectionResult};
+use crate::light_transport::FColor;
+use crate::ray::Ray;
+use crate::scene::Scene;
+
+/// Arbitrary output variables: per-pixel passes rendered next to the color, for compositing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// distance from the camera to the first hit
+    Depth,
+    /// world space position of the first hit
+    Position,
+    /// normal at the first hit, facing the camera
+    Normal,
+    /// base color at the first hit
+    Albedo,
+    /// `idx` of the hit triangle
+    TriangleIndex,
+    /// glTF material index plus one, zero for the default material
+    MaterialId,
+    DirectDiffuse,
+    DirectSpecular,
+    IndirectDiffuse,
+    IndirectSpecular,
+    /// emissive surfaces and the background seen directly
+    Emission,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 11] = [
+        Aov::Depth,
+        Aov::Position,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::TriangleIndex,
+        Aov::MaterialId,
+        Aov::DirectDiffuse,
+        Aov::DirectSpecular,
+        Aov::IndirectDiffuse,
+        Aov::IndirectSpecular,
+        Aov::Emission,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::TriangleIndex => "triangle",
+            Aov::MaterialId => "material",
+            Aov::DirectDiffuse => "direct_diffuse",
+            Aov::DirectSpecular => "direct_specular",
+            Aov::IndirectDiffuse => "indirect_diffuse",
+            Aov::IndirectSpecular => "indirect_specular",
+            Aov::Emission => "emission",
+        }
+    }
+
+    /// Indices are taken from the first sample of a pixel, averaging them would make up new ones
+    fn is_id(self) -> bool {
+        matches!(self, Aov::TriangleIndex | Aov::MaterialId)
+    }
+
+    /// EXR channel names, passes holding a single number only use the first component
+    fn channels(self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::TriangleIndex | Aov::MaterialId => &["id"],
+            Aov::Position | Aov::Normal => &["X", "Y", "Z"],
+            _ => &["R", "G", "B"],
+        }
+    }
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Aov::ALL
+            .into_iter()
+            .find(|aov| aov.name() == s)
+            .ok_or(format!("unknown aov {s}"))
+    }
+}
+
+/// Values of `aovs` for one camera ray. `paths` are the integrator's light paths, if it tracks them.
+/// Rays missing the scene leave the geometric passes at zero.
+pub fn evaluate(aovs: &[Aov], ray: &Ray, scene: &Scene, paths: Option<LightPaths>) -> Vec<FColor> {
+    let hit = if aovs.iter().any(|aov| matches!(
+        aov,
+        Aov::Depth | Aov::Position | Aov::Normal | Aov::Albedo | Aov::TriangleIndex | Aov::MaterialId
+    )) {
+        scene.geometry.intersect(ray)
+    } else {
+        IntersectionResult::Miss
+    };
+    let paths = paths.unwrap_or(LightPaths::BLACK);
+
+    aovs.iter()
+        .map(|aov| match (aov, &hit) {
+            (Aov::DirectDiffuse, _) => paths.direct_diffuse,
+            (Aov::DirectSpecular, _) => paths.direct_specular,
+            (Aov::IndirectDiffuse, _) => paths.indirect_diffuse,
+            (Aov::IndirectSpecular, _) => paths.indirect_specular,
+            (Aov::Emission, _) => paths.emission,
+            (_, IntersectionResult::Miss) => FColor::BLACK,
+            (Aov::Depth, IntersectionResult::Hit { t, .. }) => FColor::from([t * ray.dir.length(); 3]),
+            (Aov::Position, IntersectionResult::Hit { point, .. }) => FColor { rgb: point.0 },
+            (Aov::Normal, IntersectionResult::Hit { normal, .. }) => {
+                let facing_normal = if normal.dotp(ray.dir) > 0. { -*normal } else { *normal };
+                FColor { rgb: facing_normal.0 }
+            }
+            (Aov::Albedo, IntersectionResult::Hit { material, .. }) => material.color,
+            (Aov::TriangleIndex, IntersectionResult::Hit { idx, .. }) => FColor::from([*idx as f64; 3]),
+            (Aov::MaterialId, IntersectionResult::Hit { material, .. }) => {
+                FColor::from([material.id.map_or(0., |id| id as f64 + 1.); 3])
+            }
+        })
+        .collect()
+}
+
+/// The AOVs of a whole image
+pub struct AovBuffers {
+    pub aovs: Vec<Aov>,
+    width: usize,
+    height: usize,
+    /// one buffer per AOV, row by row
+    layers: Vec<Vec<FColor>>,
+}
+
+impl AovBuffers {
+    pub fn new(aovs: Vec<Aov>, width: usize, height: usize) -> Self {
+        let layers = vec![vec![FColor::BLACK; width * height]; aovs.len()];
+        AovBuffers {
+            aovs,
+            width,
+            height,
+            layers,
+        }
+    }
+
+    /// The buffer of `aov`, if it is rendered
+    pub fn get(&self, aov: Aov) -> Option<&[FColor]> {
+        let layer = self.aovs.iter().position(|a| *a == aov)?;
+        Some(&self.layers[layer])
+    }
+
+    /// Adds the values `evaluate` returned for more samples of a pixel to the mean of the `taken` ones before,
+    /// so the light passes keep adding up to the image however many batches it is sampled in
+    pub fn add_samples(&mut self, x: usize, y: usize, taken: usize, samples: &[Vec<FColor>]) {
+        let Some(first) = samples.first() else {
+            return;
+        };
+        let p = y * self.width + x;
+        let total = (taken + samples.len()) as f64;
+        for (layer, aov) in self.aovs.iter().enumerate() {
+            let pixel = &mut self.layers[layer][p];
+            if aov.is_id() {
+                if taken == 0 {
+                    *pixel = first[layer];
+                }
+            } else {
+                let sum = samples.iter().fold(*pixel * taken as f64, |sum, values| sum + values[layer]);
+                *pixel = sum * (1. / total);
+            }
+        }
+    }
+
+    /// Writes the buffers for a checkpoint
+    pub fn write_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u64(w, self.layers.len() as u64)?;
+        self.write_pixels(w, 0..self.width * self.height)
+    }
+
+    /// Reads back what `write_state` wrote, the same AOVs have to be rendered
+    pub fn read_state<R: Read>(&mut self, r: &mut R) -> io::Result<()> {
+        if read_u64(r)? != self.layers.len() as u64 {
+            return Err(invalid("the checkpoint has other AOVs"));
+        }
+        self.read_pixels(r, 0..self.width * self.height)
+    }
+
+    /// Writes every AOV of the pixels at `indices`
+    pub fn write_pixels<W: Write>(&self, w: &mut W, indices: impl Iterator<Item = usize>) -> io::Result<()> {
+        indices
+            .flat_map(|p| self.layers.iter().map(move |layer| layer[p]))
+            .try_for_each(|color| write_colors(w, &[color]))
+    }
+
+    /// Reads back what `write_pixels` wrote for the same `indices`
+    pub fn read_pixels<R: Read>(&mut self, r: &mut R, indices: impl Iterator<Item = usize>) -> io::Result<()> {
+        for p in indices {
+            for layer in &mut self.layers {
+                read_colors(r, std::slice::from_mut(&mut layer[p]))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes each of `aovs` to its own EXR file next to `output`, e.g. `render.depth.exr`
+    pub fn save_separate<P: AsRef<Path>>(&self, aovs: &[Aov], output: P) -> ImageResult<()> {
+        for (aov, layer) in self.saved(aovs) {
+            let data = layer.iter().flat_map(|c| c.rgb.map(|x| x as f32)).collect();
+            let img = Rgb32FImage::from_raw(self.width as u32, self.height as u32, data)
+                .expect("buffer matches the image size");
+            img.save(output.as_ref().with_extension(format!("{}.exr", aov.name())))?;
+        }
+        Ok(())
+    }
+
+    /// Writes `aovs` as the layers of one multi-layer EXR file
+    pub fn save_layered<P: AsRef<Path>>(&self, aovs: &[Aov], path: P) -> ImageResult<()> {
+        let size = (self.width, self.height);
+        let layers: Vec<_> = self
+            .saved(aovs)
+            .map(|(aov, layer)| {
+                let channels = aov
+                    .channels()
+                    .iter()
+                    .enumerate()
+                    .map(|(c, name)| {
+                        let samples = layer.iter().map(|color| color.rgb[c] as f32).collect();
+                        AnyChannel::new(*name, FlatSamples::F32(samples))
+                    })
+                    .collect::<Vec<_>>();
+                Layer::new(
+                    size,
+                    LayerAttributes::named(aov.name()),
+                    Encoding::FAST_LOSSLESS,
+                    AnyChannels::sort(channels.into()),
+                )
+            })
+            .collect();
+
+        let attributes = ImageAttributes::new(IntegerBounds::from_dimensions(size));
+        Image::from_layers(attributes, layers)
+            .write()
+            .to_file(path)
+            .map_err(|err| ImageError::IoError(std::io::Error::other(err)))
+    }
+
+    /// The rendered buffers among `aovs`
+    fn saved<'a>(&'a self, aovs: &'a [Aov]) -> impl Iterator<Item = (&'a Aov, &'a Vec<FColor>)> {
+        self.aovs
+            .iter()
+            .zip(&self.layers)
+            .filter(|(aov, _)| aovs.contains(aov))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_added_in_batches_average_like_one_batch() {
+        let aovs = vec![Aov::DirectDiffuse, Aov::TriangleIndex];
+        let samples: Vec<Vec<FColor>> = (0..6)
+            .map(|i| vec![FColor::from([i as f64, 1., 0.5 * i as f64]), FColor::from([10. + i as f64; 3])])
+            .collect();
+
+        let mut at_once = AovBuffers::new(aovs.clone(), 1, 1);
+        at_once.add_samples(0, 0, 0, &samples);
+        let mut batched = AovBuffers::new(aovs, 1, 1);
+        batched.add_samples(0, 0, 0, &samples[..2]);
+        batched.add_samples(0, 0, 2, &samples[2..]);
+
+        let light = batched.get(Aov::DirectDiffuse).unwrap()[0];
+        assert!((0..3).all(|c| (light.rgb[c] - at_once.get(Aov::DirectDiffuse).unwrap()[0].rgb[c]).abs() < 1e-12));
+        assert_eq!(light.rgb, [2.5, 1., 1.25]);
+        // indices stay those of the first sample
+        assert_eq!(batched.get(Aov::TriangleIndex).unwrap()[0].rgb, [10.; 3]);
+    }
+}
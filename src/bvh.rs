@@ -0,0 +1,225 @@
+use crate::intersections::Aabb;
+use crate::maths::Vec3;
+use crate::ray::Ray;
+
+/// Leaves hold at most this many items, splitting further costs more box tests than it saves
+const MAX_LEAF_ITEMS: usize = 4;
+
+/// Bounding volume hierarchy over a list of boxes, e.g. the triangles of a mesh or the instances of a scene.
+/// It only knows the boxes, what is inside is tested by the callers of `traverse` and `any`.
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    /// the root comes first, a split node's children are next to each other
+    nodes: Vec<BvhNode>,
+    /// item indices, the items of a leaf are consecutive
+    items: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    bounds: Aabb,
+    /// index of the first child for split nodes, of the first item for leaves
+    first: usize,
+    /// number of items, 0 for split nodes
+    count: usize,
+}
+
+impl Bvh {
+    /// Sorts the boxes by their centers along the axis the centers spread widest on and splits them at the median,
+    /// until few are left per leaf
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            items: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            bvh.nodes.push(BvhNode { bounds: Aabb::EMPTY, first: 0, count: bounds.len() });
+            bvh.split(0, bounds);
+        }
+        bvh
+    }
+
+    fn split(&mut self, node: usize, bounds: &[Aabb]) {
+        let BvhNode { first, count, .. } = self.nodes[node];
+        let items = &mut self.items[first..first + count];
+        let node_bounds = items.iter().fold(Aabb::EMPTY, |b, item| b.union(bounds[*item]));
+        self.nodes[node].bounds = node_bounds;
+        if count <= MAX_LEAF_ITEMS {
+            return;
+        }
+
+        let mut centers = Aabb::EMPTY;
+        items.iter().for_each(|item| centers.grow(bounds[*item].center()));
+        let extent = centers.max - centers.min;
+        let axis = (0..3).fold(0, |widest, axis| if extent.0[axis] > extent.0[widest] { axis } else { widest });
+        if extent.0[axis] <= 0. {
+            // all centers in one spot, no split separates them
+            return;
+        }
+
+        // sorting by the center keeps the split balanced even when the items bunch up on one side
+        items.sort_unstable_by(|a, b| bounds[*a].center().0[axis].total_cmp(&bounds[*b].center().0[axis]));
+        let half = count / 2;
+
+        let children = self.nodes.len();
+        self.nodes.push(BvhNode { bounds: Aabb::EMPTY, first, count: half });
+        self.nodes.push(BvhNode { bounds: Aabb::EMPTY, first: first + half, count: count - half });
+        self.nodes[node] = BvhNode { bounds: node_bounds, first: children, count: 0 };
+        self.split(children, bounds);
+        self.split(children + 1, bounds);
+    }
+
+    /// Box around everything
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::EMPTY, |root| root.bounds)
+    }
+
+    /// Calls `hit` with the items whose boxes `ray` passes between `ray.min` and the closest hit so far,
+    /// nearer boxes first. `hit` gets that distance and returns the distance of its hit, if any.
+    pub fn traverse<F: FnMut(usize, f64) -> Option<f64>>(&self, ray: &Ray, mut hit: F) {
+        let Some(root) = self.nodes.first() else {
+            return;
+        };
+        let inv_dir = ray.dir.0.map(|d| 1. / d);
+        let mut closest = ray.max;
+        if root.bounds.entry(ray.pos, inv_dir, ray.min, closest).is_none() {
+            return;
+        }
+
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node = self.nodes[node];
+            if node.bounds.entry(ray.pos, inv_dir, ray.min, closest).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                for item in &self.items[node.first..node.first + node.count] {
+                    if let Some(t) = hit(*item, closest) {
+                        closest = closest.min(t);
+                    }
+                }
+                continue;
+            }
+
+            let near = self.nodes[node.first].bounds.entry(ray.pos, inv_dir, ray.min, closest);
+            let far = self.nodes[node.first + 1].bounds.entry(ray.pos, inv_dir, ray.min, closest);
+            // the nearer child is popped first, its hits cut the other one short
+            match (near, far) {
+                (Some(a), Some(b)) if a > b => stack.extend([node.first, node.first + 1]),
+                (Some(_), Some(_)) => stack.extend([node.first + 1, node.first]),
+                (Some(_), None) => stack.push(node.first),
+                (None, Some(_)) => stack.push(node.first + 1),
+                (None, None) => {}
+            }
+        }
+    }
+
+    /// Whether `any` is true for one of the items whose boxes `ray` passes between `ray.min` and `ray.max`
+    pub fn any<F: FnMut(usize) -> bool>(&self, ray: &Ray, mut any: F) -> bool {
+        let inv_dir = ray.dir.0.map(|d| 1. / d);
+        let mut stack = if self.nodes.is_empty() { vec![] } else { vec![0] };
+        while let Some(node) = stack.pop() {
+            let node = self.nodes[node];
+            if node.bounds.entry(ray.pos, inv_dir, ray.min, ray.max).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                if self.items[node.first..node.first + node.count].iter().any(|item| any(*item)) {
+                    return true;
+                }
+            } else {
+                stack.extend([node.first, node.first + 1]);
+            }
+        }
+        false
+    }
+}
+
+impl Aabb {
+    /// Where a ray from `pos` with the reciprocal direction `inv_dir` enters the box between `min` and `max`
+    fn entry(&self, pos: Vec3, inv_dir: [f64; 3], min: f64, max: f64) -> Option<f64> {
+        let (mut near, mut far) = (min, max);
+        for (axis, inv_dir) in inv_dir.iter().enumerate() {
+            let t0 = (self.min.0[axis] - pos.0[axis]) * inv_dir;
+            let t1 = (self.max.0[axis] - pos.0[axis]) * inv_dir;
+            // NaN from 0 * inf on a slab boundary keeps the interval as it is
+            let (t0, t1) = if t0 <= t1 { (t0, t1) } else { (t1, t0) };
+            if t0 > near {
+                near = t0;
+            }
+            if t1 < far {
+                far = t1;
+            }
+            if near > far {
+                return None;
+            }
+        }
+        Some(near)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::{uniform_sphere, Sampler};
+
+    fn point(sampler: &mut Sampler, scale: f64) -> Vec3 {
+        Vec3([(); 3].map(|_| (sampler.next_f64() * 2. - 1.) * scale))
+    }
+
+    /// Boxes of all sizes, some overlapping, and rays from everywhere ending at all distances
+    fn scene(sampler: &mut Sampler) -> (Vec<Aabb>, Vec<Ray>) {
+        let boxes = (0..300)
+            .map(|_| {
+                let center = point(sampler, 10.);
+                let half = Vec3([(); 3].map(|_| sampler.next_f64() * 1.5));
+                Aabb { min: center - half, max: center + half }
+            })
+            .collect();
+        let rays = (0..1000)
+            .map(|_| Ray {
+                pos: point(sampler, 15.),
+                dir: uniform_sphere(sampler.next_2d()),
+                min: 0.,
+                max: if sampler.next_f64() < 0.5 { f64::INFINITY } else { sampler.next_f64() * 20. },
+                time: 0.,
+            })
+            .collect();
+        (boxes, rays)
+    }
+
+    fn entry(bounds: &Aabb, ray: &Ray, max: f64) -> Option<f64> {
+        bounds.entry(ray.pos, ray.dir.0.map(|d| 1. / d), ray.min, max)
+    }
+
+    #[test]
+    fn traversal_finds_the_closest_hit_of_brute_force() {
+        let mut sampler = Sampler::new(1);
+        let (boxes, rays) = scene(&mut sampler);
+        let bvh = Bvh::build(&boxes);
+
+        for ray in &rays {
+            let brute_force = boxes.iter().filter_map(|b| entry(b, ray, ray.max)).reduce(f64::min);
+            let mut closest = None;
+            bvh.traverse(ray, |item, max| {
+                let t = entry(&boxes[item], ray, max)?;
+                closest = Some(closest.map_or(t, |c: f64| c.min(t)));
+                Some(t)
+            });
+            assert_eq!(closest, brute_force, "{ray:?}");
+
+            let any = boxes.iter().any(|b| entry(b, ray, ray.max).is_some());
+            assert_eq!(bvh.any(ray, |item| entry(&boxes[item], ray, ray.max).is_some()), any, "{ray:?}");
+        }
+    }
+
+    #[test]
+    fn every_box_ends_up_in_one_leaf() {
+        let (boxes, _) = scene(&mut Sampler::new(2));
+        let bvh = Bvh::build(&boxes);
+        let mut items = bvh.items.clone();
+        items.sort_unstable();
+        assert_eq!(items, (0..boxes.len()).collect::<Vec<_>>());
+        assert!(bvh.nodes.iter().all(|node| node.count <= MAX_LEAF_ITEMS));
+    }
+}
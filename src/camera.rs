@@ -1,6 +1,6 @@
 use crate::maths::Vec3;
 use crate::ray::Ray;
-use cgmath::{Matrix3, Matrix4, One, Vector3};
+use cgmath::{Matrix3, Vector3};
 
 #[derive(Clone)]
 pub struct Camera {
@@ -11,6 +11,16 @@ pub struct Camera {
 }
 
 impl Camera {
+    /// The camera at shutter `time` on its way to `close`, where it is when the shutter closes
+    pub fn lerp(&self, close: &Camera, time: f64) -> Camera {
+        Camera {
+            position: self.position + time * (close.position - self.position),
+            direction: (self.direction + time * (close.direction - self.direction)).normalize(),
+            up: (self.up + time * (close.up - self.up)).normalize(),
+            fov: self.fov,
+        }
+    }
+
     // ISBN: 978-1-4842-7185-8, page 43
     pub fn ray_cam_to_world(&self, ray: &Ray) -> Ray {
         let right = self.direction.cross(self.up);
@@ -37,6 +47,7 @@ impl Camera {
             dir: Vec3::new(dir.x, dir.y, dir.z),
             min: ray.min,
             max: ray.max,
+            time: ray.time,
         }
     }
 }
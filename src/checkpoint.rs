@@ -0,0 +1,250 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::light_transport::{FColor, Light};
+use crate::renderer::{RenderProgress, Renderer};
+use crate::scene::Scene;
+
+/// Start of every checkpoint file, the digit is bumped when the layout changes
+const MAGIC: &[u8; 8] = b"REICKPT1";
+
+/// Saves the render state every `interval`, so a killed render can be resumed.
+/// Files are written next to `path` first and then moved over it, a kill while saving keeps the last one.
+pub struct Checkpoints {
+    pub path: PathBuf,
+    pub interval: Duration,
+    last: Instant,
+}
+
+impl Checkpoints {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        Checkpoints {
+            path,
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn due(&self) -> bool {
+        self.last.elapsed() >= self.interval
+    }
+
+    /// Writes everything `load` needs to continue at `progress`
+    pub fn save(&mut self, scene_hash: u64, progress: &RenderProgress, renderer: &Renderer) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        w.write_all(MAGIC)?;
+        write_u64(&mut w, scene_hash)?;
+        for n in [progress.pass, progress.spp, progress.row] {
+            write_u64(&mut w, n as u64)?;
+        }
+        renderer.write_state(&mut w)?;
+        w.into_inner()?.sync_all()?;
+        std::fs::rename(tmp, &self.path)?;
+
+        self.last = Instant::now();
+        Ok(())
+    }
+}
+
+/// Restores the render state saved to `path`, after checking it belongs to the scene hashing to `scene_hash`
+pub fn load(path: &Path, scene_hash: u64, renderer: &mut Renderer) -> io::Result<RenderProgress> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a checkpoint file"));
+    }
+    if read_u64(&mut r)? != scene_hash {
+        return Err(invalid("the checkpoint was saved for another scene or other settings"));
+    }
+    let progress = RenderProgress {
+        pass: read_u64(&mut r)? as usize,
+        spp: read_u64(&mut r)? as usize,
+        row: read_u64(&mut r)? as usize,
+    };
+    renderer.read_state(&mut r)?;
+    Ok(progress)
+}
+
+/// Fingerprint of what shapes the image: the contents of the scene `files`, the lights placed in the scene and the
+/// render `options`, which have to include everything the scene is posed with. The triangles follow from the files
+/// and the options. A checkpoint is only resumed when it matches, mixing the samples of two scenes would go unnoticed.
+pub fn scene_hash<O: Debug>(files: &[&[u8]], scene: &Scene, options: O) -> u64 {
+    let mut hasher = Fnv::default();
+    for file in files {
+        hasher.write(file);
+    }
+    // the Debug output of floats reads back to the same number, so it covers every bit
+    for light in &scene.lights {
+        let light = match light {
+            Light::Point(light) => format!("{light:?}"),
+            Light::Spot(light) => format!("{light:?}"),
+            Light::Directional(light) => format!("{light:?}"),
+            Light::Rect(light) => format!("{light:?}"),
+            Light::Disk(light) => format!("{light:?}"),
+            Light::Sphere(light) => format!("{light:?}"),
+            // made of their file and options
+            Light::Environment(_) => "environment".to_string(),
+            Light::Sky(_) => "sky".to_string(),
+        };
+        hasher.write(light.as_bytes());
+    }
+    hasher.write(format!("{options:?}").as_bytes());
+    hasher.finish()
+}
+
+/// 64-bit FNV-1a, unlike the std hasher it is the same for every build, checkpoints outlive the binary
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// numbers are stored as their little endian bits, reading them back gives exactly the same values
+
+pub fn write_u64<W: Write>(w: &mut W, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn write_f64<W: Write>(w: &mut W, value: f64) -> io::Result<()> {
+    write_u64(w, value.to_bits())
+}
+
+pub fn read_f64<R: Read>(r: &mut R) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(r)?))
+}
+
+pub fn write_colors<W: Write>(w: &mut W, colors: &[FColor]) -> io::Result<()> {
+    colors
+        .iter()
+        .flat_map(|c| c.rgb)
+        .try_for_each(|x| write_f64(w, x))
+}
+
+pub fn read_colors<R: Read>(r: &mut R, colors: &mut [FColor]) -> io::Result<()> {
+    for color in colors {
+        for x in &mut color.rgb {
+            *x = read_f64(r)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::Aov;
+    use crate::camera::Camera;
+    use crate::maths::{Vec2, Vec3};
+    use crate::path_tracer::PathTracer;
+    use crate::ray::{CameraFovDirection, PinholePerspective};
+    use crate::render;
+    use crate::renderer::Convergence;
+    use crate::settings::Settings;
+
+    const SPP: usize = 2;
+
+    fn scene() -> Scene {
+        let settings = Settings::from_args(Vec::new()).unwrap();
+        render::load_scene(&render::open_model("models/cube.gltf").unwrap(), None, &settings).unwrap()
+    }
+
+    /// A small adaptive render, its pixels converge after different numbers of passes
+    fn renderer(scene: &Scene) -> Renderer<'_> {
+        let (width, height) = (24, 16);
+        let camera = Camera {
+            position: Vec3([0., 0., 5.]),
+            direction: Vec3([0., 0., -1.]),
+            up: Vec3([0., 1., 0.]),
+            fov: 0.0,
+        };
+        let perspective = PinholePerspective {
+            camera_fov: std::f64::consts::FRAC_PI_4,
+            fov_dir: CameraFovDirection::Horizontal,
+            image_size: Vec2([width as f64, height as f64]),
+        };
+        let convergence = Convergence {
+            threshold: Some(0.05),
+            max_spp: 16,
+        };
+        Renderer::new(scene, camera, perspective, Box::new(PathTracer), convergence, vec![Aov::Depth], width, height)
+    }
+
+    /// Renders passes like `render::render` from `progress` on, stopping early after `rows` rows
+    fn render(renderer: &mut Renderer, mut progress: RenderProgress, mut rows: usize) -> Option<RenderProgress> {
+        loop {
+            while progress.row < renderer.framebuffer.height {
+                if rows == 0 {
+                    return Some(progress);
+                }
+                renderer.render_row(progress.row, progress.spp);
+                progress.row += 1;
+                rows -= 1;
+            }
+            if renderer.finished() {
+                return None;
+            }
+            progress = RenderProgress { pass: progress.pass + 1, spp: SPP, row: 0 };
+        }
+    }
+
+    fn state(renderer: &Renderer) -> Vec<u8> {
+        let mut state = Vec::new();
+        renderer.write_state(&mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn resuming_a_checkpoint_renders_the_same_image() {
+        let scene = scene();
+        let first = RenderProgress { pass: 1, spp: SPP, row: 0 };
+        let mut uninterrupted = renderer(&scene);
+        render(&mut uninterrupted, first, usize::MAX);
+
+        // killed in the middle of the second pass
+        let path = std::env::temp_dir().join(format!("rei-checkpoint-test-{}.ckpt", std::process::id()));
+        let mut checkpoints = Checkpoints::new(path.clone(), Duration::ZERO);
+        let mut killed = renderer(&scene);
+        let progress = render(&mut killed, first, 16 + 7).expect("the render took a single pass");
+        checkpoints.save(42, &progress, &killed).unwrap();
+
+        let mut resumed = renderer(&scene);
+        assert!(load(&path, 43, &mut resumed).is_err(), "resumed the checkpoint of another scene");
+        let loaded = load(&path, 42, &mut resumed).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((loaded.pass, loaded.spp, loaded.row), (progress.pass, progress.spp, progress.row));
+        assert!(state(&resumed) == state(&killed), "the checkpoint did not restore the render state");
+
+        render(&mut resumed, loaded, usize::MAX);
+        assert!(state(&resumed) == state(&uninterrupted), "the resumed render differs");
+    }
+}
@@ -0,0 +1,85 @@
+use cgmath::{InnerSpace, Matrix, Matrix4, SquareMatrix, Vector3, Zero};
+
+use crate::animation::Animations;
+
+/// Vertices of a primitive after its morph targets and skin are applied
+pub struct PosedPrimitive {
+    /// in the space of the mesh node, or in the world for skinned meshes
+    pub positions: Vec<Vector3<f32>>,
+    /// empty when the primitive has none
+    pub normals: Vec<Vector3<f32>>,
+}
+
+/// Morph target weights of the mesh at `node` at `time` seconds: animated, set on the node, or the mesh defaults
+pub fn morph_weights(node: &gltf::Node, animations: &Animations, time: f32) -> Vec<f32> {
+    animations
+        .weights(node, time)
+        .or_else(|| node.weights().map(<[f32]>::to_vec))
+        .or_else(|| node.mesh()?.weights().map(<[f32]>::to_vec))
+        .unwrap_or_default()
+}
+
+/// Joint matrices of `skin`, taking bind pose vertices to the world. `world` holds the world transform of
+/// every node by index, the pose the joints are in.
+pub fn joint_matrices(skin: &gltf::Skin, buffers: &[gltf::buffer::Data], world: &[Matrix4<f32>]) -> Vec<Matrix4<f32>> {
+    let reader = skin.reader(|buffer| Some(&buffers[buffer.index()]));
+    let mut inverse_binds = reader.read_inverse_bind_matrices().into_iter().flatten();
+
+    skin.joints()
+        .map(|joint| {
+            // missing inverse bind matrices are identities
+            let inverse_bind = inverse_binds.next().map_or(Matrix4::identity(), Matrix4::from);
+            world.get(joint.index()).copied().unwrap_or(Matrix4::identity()) * inverse_bind
+        })
+        .collect()
+}
+
+/// Blends the morph targets of `primitive` by `weights`, then skins it with `joints` when given
+/// (linear blend skinning with JOINTS_0 and WEIGHTS_0)
+pub fn pose_primitive(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+    weights: &[f32],
+    joints: Option<&[Matrix4<f32>]>,
+) -> PosedPrimitive {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+    let mut positions: Vec<Vector3<f32>> = reader.read_positions().into_iter().flatten().map(Vector3::from).collect();
+    let mut normals: Vec<Vector3<f32>> = reader.read_normals().into_iter().flatten().map(Vector3::from).collect();
+
+    for ((position_deltas, normal_deltas, _), weight) in reader.read_morph_targets().zip(weights) {
+        if *weight == 0. {
+            continue;
+        }
+        for (position, delta) in positions.iter_mut().zip(position_deltas.into_iter().flatten()) {
+            *position += *weight * Vector3::from(delta);
+        }
+        for (normal, delta) in normals.iter_mut().zip(normal_deltas.into_iter().flatten()) {
+            *normal += *weight * Vector3::from(delta);
+        }
+    }
+
+    if let (Some(joints), Some(vertex_joints), Some(vertex_weights)) = (joints, reader.read_joints(0), reader.read_weights(0)) {
+        let influences = vertex_joints.into_u16().zip(vertex_weights.into_f32());
+        for (i, (vertex_joints, vertex_weights)) in influences.enumerate().take(positions.len()) {
+            let mut skin = Matrix4::zero();
+            for (joint, weight) in vertex_joints.iter().zip(vertex_weights) {
+                if let Some(matrix) = joints.get(*joint as usize) {
+                    skin += *matrix * weight;
+                }
+            }
+            positions[i] = (skin * positions[i].extend(1.)).truncate();
+            if let Some(normal) = normals.get_mut(i) {
+                // normals follow the inverse transpose, so they stay perpendicular under non-uniform scale
+                let normal_matrix = skin.invert().unwrap_or(skin).transpose();
+                *normal = (normal_matrix * normal.extend(0.)).truncate();
+            }
+        }
+    }
+
+    for normal in &mut normals {
+        if normal.magnitude2() > 0. {
+            *normal = normal.normalize();
+        }
+    }
+    PosedPrimitive { positions, normals }
+}
@@ -0,0 +1,85 @@
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use crate::framebuffer::Framebuffer;
+use crate::light_transport::FColor;
+
+/// Filter passes, pass `i` samples pixels `2^i` apart, so the last one reaches 2 * 2^4 = 32 pixels
+const ITERATIONS: usize = 5;
+/// B3 spline, separable weights of the 5x5 kernel
+const KERNEL: [f64; 5] = [1. / 16., 1. / 4., 3. / 8., 1. / 4., 1. / 16.];
+/// How much a color difference may be before the filter stops at it, scaled by the strength
+const SIGMA_COLOR: f64 = 0.1;
+const SIGMA_NORMAL: f64 = 0.1;
+const SIGMA_ALBEDO: f64 = 0.1;
+/// Depth differences relative to the depth and pixel distance
+const SIGMA_DEPTH: f64 = 0.05;
+
+/// Per-pixel first hit AOVs the filter stops at, so edges stay sharp
+pub struct Guides<'a> {
+    pub albedo: &'a [FColor],
+    pub normal: &'a [FColor],
+    pub depth: &'a [FColor],
+}
+
+/// Edge-avoiding À-trous wavelet filter (Dammertz et al., "Edge-Avoiding À-Trous Wavelet Transform
+/// for fast Global Illumination Filtering", 2010), run on the HDR image before tone mapping.
+/// `strength` widens the color differences that still get smoothed, 0 leaves the image as is.
+pub fn denoise(framebuffer: &mut Framebuffer, guides: &Guides, strength: f64) {
+    if strength <= 0. {
+        return;
+    }
+
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        // later passes smooth what is left of the noise, they have to stop at smaller differences
+        let sigma_color = SIGMA_COLOR * strength / (1 << iteration) as f64;
+        let input = std::mem::take(&mut framebuffer.pixels);
+
+        framebuffer.pixels = (0..width * height)
+            .into_par_iter()
+            .map(|p| {
+                let (x, y) = ((p % width) as isize, (p / width) as isize);
+                let mut sum = FColor::BLACK;
+                let mut weight_sum = 0.;
+
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let qx = x + (i as isize - 2) * step;
+                        let qy = y + (j as isize - 2) * step;
+                        if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+
+                        let weight = kx
+                            * ky
+                            * edge_stop(distance2(input[p], input[q]), sigma_color)
+                            * edge_stop(distance2(guides.normal[p], guides.normal[q]), SIGMA_NORMAL)
+                            * edge_stop(distance2(guides.albedo[p], guides.albedo[q]), SIGMA_ALBEDO)
+                            * depth_stop(guides.depth[p].rgb[0], guides.depth[q].rgb[0], step as f64);
+                        sum += input[q] * weight;
+                        weight_sum += weight;
+                    }
+                }
+
+                // the center pixel always has a weight
+                sum * (1. / weight_sum)
+            })
+            .collect();
+    }
+}
+
+fn distance2(a: FColor, b: FColor) -> f64 {
+    let d = a - b;
+    d.rgb.iter().map(|x| x * x).sum()
+}
+
+fn edge_stop(distance2: f64, sigma: f64) -> f64 {
+    (-distance2 / (sigma * sigma)).exp()
+}
+
+fn depth_stop(depth_p: f64, depth_q: f64, pixel_distance: f64) -> f64 {
+    let scale = SIGMA_DEPTH * depth_p.max(1e-3) * pixel_distance;
+    (-(depth_p - depth_q).abs() / scale).exp()
+}
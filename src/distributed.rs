@@ -0,0 +1,350 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+
+use crate::checkpoint::{invalid, read_u64, write_u64};
+use crate::renderer::{Renderer, Tile};
+
+/// Sent first on every connection, the digit is bumped when the protocol changes
+const MAGIC: &[u8; 8] = b"REINET03";
+/// Pixels on a side of the tiles handed out, small enough to spread the image over many workers
+const TILE_SIZE: usize = 32;
+/// A worker taking longer than this for a tile is given up on, its tile goes to another one
+const TILE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How long idle workers wait before asking for the tiles of failed ones
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// what the coordinator asks a worker for
+const MESSAGE_DONE: u64 = 0;
+const MESSAGE_TILE: u64 = 1;
+
+/// Tiles not rendered yet, shared by the connections to the workers
+struct TileQueue {
+    pending: Vec<Tile>,
+    /// tiles whose pixels did not arrive yet, pending or being rendered
+    unfinished: usize,
+}
+
+/// Renders `renderer`'s image with the workers connecting to `listener`.
+/// Workers get the render options `args`, the glTF file `model`, a .glb or self-contained .gltf,
+/// and the `--environment` map `environment`, and have to load a scene hashing to `scene_hash` from them.
+/// When a worker disconnects or times out, its tile is handed to the next free one.
+pub fn coordinate(
+    listener: TcpListener,
+    args: &[String],
+    model: &[u8],
+    environment: Option<&[u8]>,
+    scene_hash: u64,
+    renderer: &mut Renderer,
+) -> io::Result<()> {
+    println!("Waiting for workers on {}..", listener.local_addr()?);
+
+    let mut tiles = Tile::split(renderer.framebuffer.width, renderer.framebuffer.height, TILE_SIZE);
+    // hand out the top left tile first
+    tiles.reverse();
+    let total = tiles.len();
+    let queue = Arc::new(Mutex::new(TileQueue {
+        pending: tiles,
+        unfinished: total,
+    }));
+    let (results, rendered) = mpsc::channel();
+    let args = Arc::new(args.to_vec());
+    let files = Arc::new((model.to_vec(), environment.map(<[u8]>::to_vec)));
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let (queue, results, args, files) = (queue.clone(), results.clone(), args.clone(), files.clone());
+            thread::spawn(move || {
+                let peer = stream.peer_addr().map_or("?".to_string(), |addr| addr.to_string());
+                println!("Worker {peer} connected");
+                match serve_worker(stream, &args, &files.0, files.1.as_deref(), scene_hash, &queue, &results) {
+                    Ok(()) => println!("Worker {peer} is done"),
+                    Err(err) => println!("Worker {peer} failed: {err}"),
+                }
+            });
+        }
+    });
+
+    let bar = ProgressBar::new(total as u64);
+    for _ in 0..total {
+        let (tile, pixels): (Tile, Vec<u8>) = rendered.recv().expect("the listener keeps a sender");
+        renderer.read_tile(&mut pixels.as_slice(), tile)?;
+        bar.inc(1);
+    }
+    bar.finish();
+    Ok(())
+}
+
+/// Hands out tiles to one worker until none are left, putting its tile back when it fails
+fn serve_worker(
+    stream: TcpStream,
+    args: &[String],
+    model: &[u8],
+    environment: Option<&[u8]>,
+    scene_hash: u64,
+    queue: &Mutex<TileQueue>,
+    results: &Sender<(Tile, Vec<u8>)>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(TILE_TIMEOUT))?;
+    let mut r = BufReader::new(stream.try_clone()?);
+    let mut w = BufWriter::new(stream);
+
+    w.write_all(MAGIC)?;
+    write_u64(&mut w, args.len() as u64)?;
+    for arg in args {
+        write_bytes(&mut w, arg.as_bytes())?;
+    }
+    write_bytes(&mut w, model)?;
+    write_u64(&mut w, environment.is_some() as u64)?;
+    if let Some(environment) = environment {
+        write_bytes(&mut w, environment)?;
+    }
+    w.flush()?;
+    if read_u64(&mut r)? != scene_hash {
+        return Err(invalid("the worker loaded another scene"));
+    }
+
+    loop {
+        let next = {
+            let mut queue = queue.lock().unwrap();
+            match queue.pending.pop() {
+                Some(tile) => Some(tile),
+                None if queue.unfinished == 0 => None,
+                // other workers still render, wait in case one of them fails
+                None => {
+                    drop(queue);
+                    thread::sleep(RETRY_DELAY);
+                    continue;
+                }
+            }
+        };
+        let Some(tile) = next else {
+            write_u64(&mut w, MESSAGE_DONE)?;
+            return w.flush();
+        };
+
+        match render_remotely(&mut r, &mut w, tile) {
+            Ok(pixels) => {
+                queue.lock().unwrap().unfinished -= 1;
+                // the coordinator stops listening once it has every tile
+                let _ = results.send((tile, pixels));
+            }
+            Err(err) => {
+                queue.lock().unwrap().pending.push(tile);
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Asks the worker for `tile` and waits for its pixels
+fn render_remotely<R: Read, W: Write>(r: &mut R, w: &mut W, tile: Tile) -> io::Result<Vec<u8>> {
+    write_u64(w, MESSAGE_TILE)?;
+    for n in [tile.x0, tile.y0, tile.x1, tile.y1] {
+        write_u64(w, n as u64)?;
+    }
+    w.flush()?;
+    read_bytes(r)
+}
+
+/// A connection to the coordinator, renders the tiles it asks for
+pub struct Worker {
+    /// render options of the coordinator
+    pub args: Vec<String>,
+    /// the glTF file to render, for `GltfFile::from_slice`
+    pub model: Vec<u8>,
+    /// the `--environment` map of the coordinator
+    pub environment: Option<Vec<u8>>,
+    r: BufReader<TcpStream>,
+    w: BufWriter<TcpStream>,
+}
+
+impl Worker {
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        let mut r = BufReader::new(stream.try_clone()?);
+        let w = BufWriter::new(stream);
+
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a rei coordinator"));
+        }
+        let args = (0..read_u64(&mut r)?)
+            .map(|_| String::from_utf8(read_bytes(&mut r)?).map_err(|_| invalid("option is not UTF-8")))
+            .collect::<io::Result<_>>()?;
+        let model = read_bytes(&mut r)?;
+        let environment = match read_u64(&mut r)? {
+            0 => None,
+            _ => Some(read_bytes(&mut r)?),
+        };
+
+        Ok(Worker { args, model, environment, r, w })
+    }
+
+    /// Renders tiles with `renderer` until the coordinator is done, see `Renderer::render_tile` for `spp` and `batch`
+    pub fn serve(mut self, scene_hash: u64, renderer: &mut Renderer, spp: usize, batch: usize) -> io::Result<()> {
+        write_u64(&mut self.w, scene_hash)?;
+        self.w.flush()?;
+
+        loop {
+            match read_u64(&mut self.r)? {
+                MESSAGE_DONE => return Ok(()),
+                MESSAGE_TILE => {
+                    let tile = Tile {
+                        x0: read_u64(&mut self.r)? as usize,
+                        y0: read_u64(&mut self.r)? as usize,
+                        x1: read_u64(&mut self.r)? as usize,
+                        y1: read_u64(&mut self.r)? as usize,
+                    };
+                    let (width, height) = (renderer.framebuffer.width, renderer.framebuffer.height);
+                    if tile.x0 > tile.x1 || tile.y0 > tile.y1 || tile.x1 > width || tile.y1 > height {
+                        return Err(invalid("tile outside of the image"));
+                    }
+                    renderer.render_tile(tile, spp, batch);
+
+                    let mut pixels = Vec::new();
+                    renderer.write_tile(&mut pixels, tile)?;
+                    write_bytes(&mut self.w, &pixels)?;
+                    self.w.flush()?;
+                }
+                _ => return Err(invalid("unknown message")),
+            }
+        }
+    }
+}
+
+/// Length and bytes
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u64(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Barrier;
+
+    use image::{DynamicImage, ImageFormat, Rgb, Rgb32FImage};
+
+    use super::*;
+    use crate::camera::Camera;
+    use crate::checkpoint;
+    use crate::maths::{Vec2, Vec3};
+    use crate::ray::{CameraFovDirection, PinholePerspective};
+    use crate::render;
+    use crate::renderer::Convergence;
+    use crate::scene::Scene;
+    use crate::settings::Settings;
+    use crate::whitted::Whitted;
+
+    const MODEL: &str = "models/cube.gltf";
+    const SPP: usize = 2;
+
+    fn load(model: &[u8], environment: &[u8]) -> (Scene, u64) {
+        let settings = Settings::from_args(Vec::new()).unwrap();
+        let scene = render::load_scene(&render::model_from_slice(model).unwrap(), Some(environment), &settings).unwrap();
+        let scene_hash = checkpoint::scene_hash(&[model, environment], &scene, ());
+        (scene, scene_hash)
+    }
+
+    /// A sky brighter towards the top as an .exr file
+    fn environment() -> Vec<u8> {
+        let sky = Rgb32FImage::from_fn(8, 4, |_, y| Rgb([0.2, 0.3, 1. - y as f32 / 4.]));
+        let mut exr = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb32F(sky).write_to(&mut exr, ImageFormat::OpenExr).unwrap();
+        exr.into_inner()
+    }
+
+    /// A small image, a few tiles wide
+    fn renderer(scene: &Scene) -> Renderer<'_> {
+        let (width, height) = (TILE_SIZE * 2 + 5, TILE_SIZE + 3);
+        let camera = Camera {
+            position: Vec3([0., 0., 5.]),
+            direction: Vec3([0., 0., -1.]),
+            up: Vec3([0., 1., 0.]),
+            fov: 0.0,
+        };
+        let perspective = PinholePerspective {
+            camera_fov: std::f64::consts::FRAC_PI_4,
+            fov_dir: CameraFovDirection::Horizontal,
+            image_size: Vec2([width as f64, height as f64]),
+        };
+        let convergence = Convergence {
+            threshold: None,
+            max_spp: SPP,
+        };
+        let integrator = Box::new(Whitted { shadow_samples: 1 });
+        Renderer::new(scene, camera, perspective, integrator, convergence, Vec::new(), width, height)
+    }
+
+    fn image(renderer: &Renderer) -> Vec<u8> {
+        let (width, height) = (renderer.framebuffer.width, renderer.framebuffer.height);
+        let mut pixels = Vec::new();
+        renderer.write_tile(&mut pixels, Tile { x0: 0, y0: 0, x1: width, y1: height }).unwrap();
+        pixels
+    }
+
+    #[test]
+    fn tiles_of_a_dropped_worker_are_rendered_by_another() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (model, environment) = (std::fs::read(MODEL).unwrap(), environment());
+        let (scene, scene_hash) = load(&model, &environment);
+
+        let mut alone = renderer(&scene);
+        for tile in Tile::split(alone.framebuffer.width, alone.framebuffer.height, TILE_SIZE) {
+            alone.render_tile(tile, SPP, SPP);
+        }
+
+        // the first worker takes a tile and goes away without rendering it, then two others start
+        let dropped = Arc::new(Barrier::new(3));
+        let (drop_worker, drop_address) = (dropped.clone(), address.clone());
+        thread::spawn(move || {
+            let mut worker = Worker::connect(&drop_address).unwrap();
+            write_u64(&mut worker.w, scene_hash).unwrap();
+            worker.w.flush().unwrap();
+            assert_eq!(read_u64(&mut worker.r).unwrap(), MESSAGE_TILE);
+            drop(worker);
+            drop_worker.wait();
+        });
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let (dropped, address) = (dropped.clone(), address.clone());
+                thread::spawn(move || {
+                    dropped.wait();
+                    let worker = Worker::connect(&address).unwrap();
+                    let (scene, scene_hash) = load(&worker.model, worker.environment.as_deref().unwrap());
+                    worker.serve(scene_hash, &mut renderer(&scene), SPP, SPP).unwrap();
+                })
+            })
+            .collect();
+
+        let mut coordinator = renderer(&scene);
+        coordinate(listener, &[], &model, Some(&environment), scene_hash, &mut coordinator).unwrap();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert!(coordinator.finished());
+        assert!(image(&coordinator) == image(&alone), "the workers rendered another image");
+    }
+}
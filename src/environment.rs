@@ -0,0 +1,118 @@
+use std::f64::consts::PI;
+
+use crate::light_transport::{FColor, LightSample};
+use crate::maths::Vec3;
+use crate::sampling::Distribution2D;
+
+/// Equirectangular environment map (.hdr or .exr) lighting the scene from infinitely far away.
+/// +y is up, the center of the image looks down -z.
+pub struct EnvironmentLight {
+    width: usize,
+    height: usize,
+    texels: Vec<FColor>,
+    /// radians, counterclockwise around +y
+    pub rotation: f64,
+    /// scales the radiance stored in the map
+    pub intensity: f64,
+    /// texels weighted by luminance and solid angle
+    distribution: Distribution2D,
+}
+
+impl EnvironmentLight {
+    /// Decodes the .hdr or .exr file `bytes`
+    pub fn from_slice(bytes: &[u8], rotation: f64, intensity: f64) -> image::ImageResult<Self> {
+        let img = image::load_from_memory(bytes)?.into_rgb32f();
+        let (width, height) = (img.width() as usize, img.height() as usize);
+
+        let texels: Vec<FColor> = img
+            .pixels()
+            .map(|p| FColor { rgb: p.0.map(|x| x as f64) })
+            .collect();
+
+        Ok(Self::from_texels(width, height, texels, rotation, intensity))
+    }
+
+    /// Map from texels stored row by row, top row first
+    pub fn from_texels(width: usize, height: usize, texels: Vec<FColor>, rotation: f64, intensity: f64) -> Self {
+        // rows near the poles cover less solid angle, weigh them down by sin(theta)
+        let func: Vec<f64> = texels
+            .iter()
+            .enumerate()
+            .map(|(idx, texel)| {
+                let theta = PI * ((idx / width) as f64 + 0.5) / height as f64;
+                texel.luminance() * theta.sin()
+            })
+            .collect();
+        let distribution = Distribution2D::new(&func, width);
+
+        EnvironmentLight {
+            width,
+            height,
+            texels,
+            rotation,
+            intensity,
+            distribution,
+        }
+    }
+
+    /// Radiance arriving from direction `dir`
+    pub fn radiance(&self, dir: Vec3) -> FColor {
+        let [u, v] = self.dir_to_uv(dir.normalize());
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.texels[y * self.width + x] * self.intensity
+    }
+
+    /// Picks a direction proportional to the brightness of the map
+    pub fn sample(&self, u: [f64; 2]) -> LightSample {
+        let (dir, pdf) = self.sample_direction(u);
+
+        LightSample {
+            dir,
+            distance: f64::INFINITY,
+            radiance: if pdf > 0. {
+                self.radiance(dir) * (1. / pdf)
+            } else {
+                FColor::BLACK
+            },
+        }
+    }
+
+    /// Direction picked proportional to the brightness of the map, and its solid angle pdf
+    pub fn sample_direction(&self, u: [f64; 2]) -> (Vec3, f64) {
+        let (uv, pdf_uv) = self.distribution.sample(u);
+        let dir = self.uv_to_dir(uv);
+
+        // from the image plane to the sphere of directions
+        let sin_theta = (PI * uv[1]).sin();
+        let pdf = if sin_theta > 0. {
+            pdf_uv / (2. * PI * PI * sin_theta)
+        } else {
+            0.
+        };
+        (dir, pdf)
+    }
+
+    /// Solid angle pdf `sample_direction` picks `dir` with
+    pub fn pdf(&self, dir: Vec3) -> f64 {
+        let uv = self.dir_to_uv(dir.normalize());
+        let sin_theta = (PI * uv[1]).sin();
+        if sin_theta > 0. {
+            self.distribution.pdf(uv) / (2. * PI * PI * sin_theta)
+        } else {
+            0.
+        }
+    }
+
+    fn dir_to_uv(&self, dir: Vec3) -> [f64; 2] {
+        let phi = dir.x().atan2(-dir.z()) - self.rotation;
+        let theta = dir.y().clamp(-1., 1.).acos();
+        [(0.5 + phi / (2. * PI)).rem_euclid(1.), theta / PI]
+    }
+
+    fn uv_to_dir(&self, uv: [f64; 2]) -> Vec3 {
+        let phi = (uv[0] - 0.5) * 2. * PI + self.rotation;
+        let theta = uv[1] * PI;
+        Vec3([theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos()])
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::{self, Read, Write};
+
+use image::{ImageBuffer, Rgb, Rgb32FImage, RgbImage};
+
+use crate::checkpoint::{invalid, read_colors, read_f64, read_u64, write_colors, write_f64, write_u64};
+use crate::light_transport::FColor;
+
+/// The rendered image in linear radiance, before it is tone mapped to 8 bits.
+/// Samples are accumulated into a running mean, with the variance of their luminance kept next to it
+/// (Welford's online algorithm), so the renderer can tell which pixels are still noisy.
+#[derive(Clone)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    /// mean of the samples, row by row
+    pub pixels: Vec<FColor>,
+    /// samples taken per pixel
+    pub counts: Vec<u32>,
+    /// sum of squared luminance deviations from the mean
+    m2: Vec<f64>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![FColor::BLACK; width * height],
+            counts: vec![0; width * height],
+            m2: vec![0.; width * height],
+        }
+    }
+
+    /// Adds one radiance sample to the pixel
+    pub fn add_sample(&mut self, x: usize, y: usize, color: FColor) {
+        let p = y * self.width + x;
+        self.counts[p] += 1;
+        let n = self.counts[p] as f64;
+
+        let old_mean = self.pixels[p];
+        let mean = old_mean + (color - old_mean) * (1. / n);
+        self.m2[p] += (color.luminance() - old_mean.luminance()) * (color.luminance() - mean.luminance());
+        self.pixels[p] = mean;
+    }
+
+    /// Standard error of the pixel's mean luminance relative to that mean, infinite with fewer than two samples.
+    /// Dark pixels get a bit of slack, noise there is hard to see.
+    pub fn relative_error(&self, x: usize, y: usize) -> f64 {
+        let p = y * self.width + x;
+        let n = self.counts[p] as f64;
+        if n < 2. {
+            return f64::INFINITY;
+        }
+        let variance = self.m2[p] / (n - 1.);
+        (variance / n).sqrt() / (self.pixels[p].luminance() + 0.1)
+    }
+
+    /// Writes the samples taken so far for a checkpoint
+    pub fn write_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u64(w, self.width as u64)?;
+        write_u64(w, self.height as u64)?;
+        self.write_pixels(w, 0..self.pixels.len())
+    }
+
+    /// Reads back what `write_state` wrote, the image has to be the same size
+    pub fn read_state<R: Read>(&mut self, r: &mut R) -> io::Result<()> {
+        if (read_u64(r)?, read_u64(r)?) != (self.width as u64, self.height as u64) {
+            return Err(invalid("the checkpoint has another image size"));
+        }
+        self.read_pixels(r, 0..self.pixels.len())
+    }
+
+    /// Writes the samples of the pixels at `indices`
+    pub fn write_pixels<W: Write>(&self, w: &mut W, indices: impl Iterator<Item = usize>) -> io::Result<()> {
+        for p in indices {
+            write_colors(w, &[self.pixels[p]])?;
+            write_u64(w, self.counts[p] as u64)?;
+            write_f64(w, self.m2[p])?;
+        }
+        Ok(())
+    }
+
+    /// Reads back what `write_pixels` wrote for the same `indices`
+    pub fn read_pixels<R: Read>(&mut self, r: &mut R, indices: impl Iterator<Item = usize>) -> io::Result<()> {
+        for p in indices {
+            read_colors(r, std::slice::from_mut(&mut self.pixels[p]))?;
+            self.counts[p] = read_u64(r)? as u32;
+            self.m2[p] = read_f64(r)?;
+        }
+        Ok(())
+    }
+
+    /// Clamps the radiance to the displayable range
+    pub fn to_image(&self) -> RgbImage {
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let color = self.pixels[y as usize * self.width + x as usize].to_color();
+            Rgb(color.into())
+        })
+    }
+
+    /// The linear radiance, e.g. for saving as EXR
+    pub fn to_hdr_image(&self) -> Rgb32FImage {
+        let data = self.pixels.iter().flat_map(|c| c.rgb.map(|x| x as f32)).collect();
+        Rgb32FImage::from_raw(self.width as u32, self.height as u32, data).expect("buffer matches the image size")
+    }
+
+    /// Samples per pixel from blue (fewest) over green to red (`max_spp`)
+    pub fn sample_heatmap(&self, max_spp: usize) -> RgbImage {
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let count = self.counts[y as usize * self.width + x as usize] as f64;
+            let t = (count / max_spp.max(1) as f64).min(1.);
+            let color = if t < 0.5 {
+                FColor::from([0., 2. * t, 1. - 2. * t])
+            } else {
+                FColor::from([2. * t - 1., 2. - 2. * t, 0.])
+            };
+            Rgb(color.to_color().into())
+        })
+    }
+}
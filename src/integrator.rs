@@ -0,0 +1,131 @@
+use crate::intersections::{Intersect, IntersectionResult};
+use crate::light_transport::FColor;
+use crate::maths::Frame;
+use crate::ray::Ray;
+use crate::sampling::{cosine_hemisphere, Sampler};
+use crate::scene::Scene;
+
+/// A rendering algorithm, turning camera rays into colors
+pub trait Integrator {
+    /// Radiance (or whatever the mode visualizes) arriving along `ray`
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> FColor;
+
+    /// The radiance along `ray` split up by the paths that carried it, for integrators simulating light
+    fn light_paths(&self, _ray: &Ray, _scene: &Scene, _sampler: &mut Sampler) -> Option<LightPaths> {
+        None
+    }
+}
+
+/// Radiance split up by the kind of path that carried it to the camera, the parts add up to the whole.
+/// Direct light reached the first surface straight from a light, indirect light scattered at least twice;
+/// diffuse and specular tell which part of the first surface's BSDF sent it on.
+#[derive(Debug, Clone, Copy)]
+pub struct LightPaths {
+    /// emissive surfaces and the background seen directly
+    pub emission: FColor,
+    pub direct_diffuse: FColor,
+    pub direct_specular: FColor,
+    pub indirect_diffuse: FColor,
+    pub indirect_specular: FColor,
+}
+
+impl LightPaths {
+    pub const BLACK: LightPaths = LightPaths {
+        emission: FColor::BLACK,
+        direct_diffuse: FColor::BLACK,
+        direct_specular: FColor::BLACK,
+        indirect_diffuse: FColor::BLACK,
+        indirect_specular: FColor::BLACK,
+    };
+
+    pub fn total(&self) -> FColor {
+        self.emission + self.direct_diffuse + self.direct_specular + self.indirect_diffuse + self.indirect_specular
+    }
+
+    /// Every part attenuated by `weight`
+    pub fn scale(self, weight: FColor) -> LightPaths {
+        LightPaths {
+            emission: self.emission * weight,
+            direct_diffuse: self.direct_diffuse * weight,
+            direct_specular: self.direct_specular * weight,
+            indirect_diffuse: self.indirect_diffuse * weight,
+            indirect_specular: self.indirect_specular * weight,
+        }
+    }
+}
+
+/// Share of the hemisphere above the first hit that is open, in gray.
+/// Rays are cosine-weighted, so occluders near the normal count most, and only ask whether anything
+/// lies within `distance` instead of searching the closest hit.
+pub struct AmbientOcclusion {
+    /// occluders further away than this do not darken the point
+    pub distance: f64,
+    pub samples: usize,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> FColor {
+        let IntersectionResult::Hit { point, normal, .. } = scene.geometry.intersect(ray) else {
+            return FColor::from([1., 1., 1.]);
+        };
+
+        let facing_normal = if normal.dotp(ray.dir) > 0. { -normal } else { normal };
+        let frame = Frame::new(facing_normal);
+        let open = (0..self.samples)
+            .filter(|_| {
+                let dir = frame.to_world(cosine_hemisphere(sampler.next_2d()));
+                !scene.geometry.occluded(&Ray::spawn(point, facing_normal, dir, self.distance, ray.time))
+            })
+            .count();
+
+        let visibility = open as f64 / self.samples.max(1) as f64;
+        FColor::from([visibility; 3])
+    }
+}
+
+/// Views of the geometry for inspecting scenes, they ignore the lights
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugView {
+    /// geometric normal, mapped from [-1, 1] to [0, 1]
+    Normals,
+    /// distance to the camera, bright is near
+    Depth,
+    /// base color of the material
+    Albedo,
+    /// barycentric coordinates of the hit as red, green and blue
+    Barycentrics,
+    /// a random color per triangle
+    TriangleIndex,
+}
+
+impl Integrator for DebugView {
+    fn li(&self, ray: &Ray, scene: &Scene, _sampler: &mut Sampler) -> FColor {
+        let IntersectionResult::Hit {
+            idx,
+            normal,
+            t,
+            barycentric,
+            material,
+            ..
+        } = scene.geometry.intersect(ray)
+        else {
+            return FColor::BLACK;
+        };
+
+        match self {
+            DebugView::Normals => FColor {
+                rgb: normal.0.map(|x| 0.5 * (x + 1.)),
+            },
+            DebugView::Depth => {
+                let depth = t * ray.dir.length();
+                FColor::from([1. / (1. + depth); 3])
+            }
+            DebugView::Albedo => material.color,
+            DebugView::Barycentrics => FColor::from(barycentric),
+            DebugView::TriangleIndex => {
+                let mut hash = Sampler::new(idx as u64);
+                FColor::from([hash.next_f64(), hash.next_f64(), hash.next_f64()])
+            }
+        }
+    }
+}
@@ -1,15 +1,24 @@
-use crate::{maths::Vec3, light_transport::PBRMaterial};
+use crate::{maths::Vec3, light_transport::{FColor, PBRMaterial}};
+use crate::bvh::Bvh;
+use crate::motion::{transform_point, transform_vector, MotionTransform};
 use crate::ray::Ray;
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator, IndexedParallelIterator};
+use crate::sampling::uniform_triangle;
+use cgmath::{Matrix, Matrix4, SquareMatrix};
 use IntersectionResult::{Hit, Miss};
 
-pub enum IntersectionResult {
+pub enum IntersectionResult<'a> {
     Hit {
         idx: usize, // Which object is hit?
         point: Vec3,
+        /// geometric normal, pointing outside of closed meshes
         normal: Vec3,
+        /// normal interpolated from the vertex normals for shading, the geometric one without them
+        shading_normal: Vec3,
         t: f64,
-        color: PBRMaterial,
+        /// barycentric coordinates of the hit point on triangles
+        barycentric: [f64; 3],
+        /// looked up in the material table of the geometry, not copied for every candidate
+        material: &'a PBRMaterial,
     },
     Miss,
 }
@@ -24,7 +33,15 @@ pub struct IntersectionCtx {
 */
 
 pub trait Intersect {
-    fn intersect(&self, ray: &Ray) -> IntersectionResult;
+    fn intersect(&self, ray: &Ray) -> IntersectionResult<'_>;
+
+    /// Any-hit query for shadow rays: is anything hit between `ray.min` and `ray.max`?
+    fn occluded(&self, ray: &Ray) -> bool {
+        match self.intersect(ray) {
+            Hit { t, .. } => t >= ray.min && t <= ray.max,
+            Miss => false,
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -33,22 +50,341 @@ pub struct Sphere {
     pub position: Vec3,
 }
 
+/// A triangle on its own, e.g. an emitter posed in the world. Scenes keep theirs in `TriangleMesh`es.
 #[derive(Copy, Clone, Debug)]
 pub struct Triangle {
     pub vertices: [Vec3; 3],
     pub pbr_mat: PBRMaterial,
 }
 
+/// Axis-aligned box
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
 pub struct Geometry<'a> {
     pub objects: Vec<&'a dyn Intersect>,
 }
 
+/// Indexed triangles in their own space with a hierarchy over them, built once however often the mesh is placed.
+/// Vertices are shared by the triangles using them, materials are IDs into `TriGeometry::materials`.
+pub struct TriangleMesh {
+    /// where the vertices are when the shutter opens
+    pub positions: Vec<Vec3>,
+    /// where the vertices are when the shutter closes, they move along straight lines.
+    /// Empty when the mesh keeps its shape.
+    pub positions_close: Vec<Vec3>,
+    /// per vertex for smooth shading, empty when the mesh has none
+    pub normals: Vec<Vec3>,
+    /// vertex indices of the triangle corners, counterclockwise
+    pub indices: Vec<[u32; 3]>,
+    /// material ID per triangle
+    pub materials: Vec<u32>,
+    bvh: Bvh,
+}
+
+/// Triangle `idx` of `mesh`, with its material looked up
+#[derive(Copy, Clone)]
+pub struct TriangleRef<'a> {
+    pub mesh: &'a TriangleMesh,
+    pub idx: usize,
+    pub material: &'a PBRMaterial,
+}
+
+/// A mesh placed in the scene
+#[derive(Debug, Clone)]
+pub struct Instance {
+    /// index into `TriGeometry::meshes`
+    pub mesh: usize,
+    /// from the mesh into the world when the shutter opens
+    pub transform: Matrix4<f64>,
+    /// how the transform changes while the shutter is open, `None` when the instance stands still
+    pub motion: Option<MotionTransform>,
+    /// material ID replacing the materials of the mesh
+    pub material: Option<u32>,
+    /// from the world into the mesh, when the shutter opens
+    inverse: Matrix4<f64>,
+}
+
+/// The triangles of a scene, meshes placed by instances with a hierarchy over the instances.
+/// Triangles are numbered through the instances, the first instance's triangles come first.
 pub struct TriGeometry {
-    pub objects: Vec<Triangle>,
+    pub meshes: Vec<TriangleMesh>,
+    /// referenced by ID from the meshes and instances
+    pub materials: Vec<PBRMaterial>,
+    pub instances: Vec<Instance>,
+    /// number of the first triangle of each instance
+    first_triangles: Vec<usize>,
+    triangle_count: usize,
+    /// over where the instances are while the shutter is open
+    bvh: Bvh,
+}
+
+/// Steps the motion of an instance is followed in for its bounds, in between it is padded
+const MOTION_BOUNDS_STEPS: usize = 16;
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3([f64::INFINITY; 3]),
+        max: Vec3([f64::NEG_INFINITY; 3]),
+    };
+
+    pub fn grow(&mut self, point: Vec3) {
+        for axis in 0..3 {
+            self.min.0[axis] = self.min.0[axis].min(point.0[axis]);
+            self.max.0[axis] = self.max.0[axis].max(point.0[axis]);
+        }
+    }
+
+    pub fn union(mut self, other: Aabb) -> Aabb {
+        self.grow(other.min);
+        self.grow(other.max);
+        self
+    }
+
+    /// Grown by `margin` on every side
+    pub fn padded(self, margin: f64) -> Aabb {
+        Aabb {
+            min: self.min - Vec3([margin; 3]),
+            max: self.max + Vec3([margin; 3]),
+        }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            let pick = |axis: usize| if i & (1 << axis) == 0 { self.min.0[axis] } else { self.max.0[axis] };
+            Vec3([pick(0), pick(1), pick(2)])
+        })
+    }
 }
 
+impl TriangleMesh {
+    /// Builds the hierarchy over the triangles, `positions_close` is empty or has one position per vertex
+    pub fn new(
+        positions: Vec<Vec3>,
+        positions_close: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        indices: Vec<[u32; 3]>,
+        materials: Vec<u32>,
+    ) -> Self {
+        let mut mesh = TriangleMesh {
+            positions,
+            positions_close,
+            normals,
+            indices,
+            materials,
+            bvh: Bvh::default(),
+        };
+        let bounds: Vec<Aabb> = (0..mesh.triangle_count()).map(|idx| mesh.triangle_bounds(idx)).collect();
+        mesh.bvh = Bvh::build(&bounds);
+        mesh
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Whether the mesh changes shape while the shutter is open
+    pub fn deforms(&self) -> bool {
+        !self.positions_close.is_empty()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    /// Corners of triangle `idx` at shutter `time`
+    pub fn vertices_at(&self, idx: usize, time: f64) -> [Vec3; 3] {
+        self.indices[idx].map(|i| {
+            let open = self.positions[i as usize];
+            match self.positions_close.get(i as usize) {
+                Some(close) => open + time * (*close - open),
+                None => open,
+            }
+        })
+    }
+
+    /// Vertex normals of triangle `idx` blended at the `barycentric` coordinates of a hit on it,
+    /// None when the mesh has no normals or they cancel out
+    pub fn shading_normal(&self, idx: usize, barycentric: [f64; 3]) -> Option<Vec3> {
+        if self.normals.is_empty() {
+            return None;
+        }
+        let [n0, n1, n2] = self.indices[idx].map(|i| self.normals[i as usize]);
+        // the barycentric coordinates weigh the second and third corner, the rest goes to the first
+        let normal = barycentric[2] * n0 + barycentric[0] * n1 + barycentric[1] * n2;
+        (normal.length() > 1e-12).then(|| normal.normalize())
+    }
+
+    /// Box around triangle `idx` over the whole time the shutter is open,
+    /// straight lines stay inside the box of their ends
+    fn triangle_bounds(&self, idx: usize) -> Aabb {
+        let mut bounds = Aabb::EMPTY;
+        self.vertices_at(idx, 0.).iter().for_each(|v| bounds.grow(*v));
+        if self.deforms() {
+            self.vertices_at(idx, 1.).iter().for_each(|v| bounds.grow(*v));
+        }
+        bounds
+    }
+}
+
+impl Instance {
+    pub fn new(mesh: usize, transform: Matrix4<f64>, motion: Option<MotionTransform>, material: Option<u32>) -> Self {
+        Instance {
+            mesh,
+            transform,
+            motion,
+            material,
+            inverse: transform.invert().unwrap_or(Matrix4::identity()),
+        }
+    }
+
+    /// From the mesh into the world at shutter `time`
+    pub fn transform_at(&self, time: f64) -> Matrix4<f64> {
+        self.motion.map_or(self.transform, |motion| motion.at(time))
+    }
+
+    /// From the world into the mesh at shutter `time`
+    fn inverse_at(&self, time: f64) -> Matrix4<f64> {
+        match self.motion {
+            Some(motion) => motion.at(time).invert().unwrap_or(Matrix4::identity()),
+            None => self.inverse,
+        }
+    }
+
+    /// Box around `mesh` placed by this instance, over the whole time the shutter is open
+    fn bounds(&self, mesh: &TriangleMesh) -> Aabb {
+        let corners = mesh.bounds().corners();
+        let mut bounds = Aabb::EMPTY;
+        let Some(motion) = self.motion else {
+            corners.iter().for_each(|c| bounds.grow(transform_point(&self.transform, *c)));
+            return bounds;
+        };
+
+        let steps: Vec<[Vec3; 8]> = (0..=MOTION_BOUNDS_STEPS)
+            .map(|step| {
+                let transform = motion.at(step as f64 / MOTION_BOUNDS_STEPS as f64);
+                corners.map(|c| transform_point(&transform, c))
+            })
+            .collect();
+        steps.iter().flatten().for_each(|c| bounds.grow(*c));
+        // between two steps a corner strays from the straight line by at most half the step
+        let longest_step = steps
+            .windows(2)
+            .flat_map(|w| (0..8).map(move |i| (w[1][i] - w[0][i]).length()))
+            .fold(0., f64::max);
+        bounds.padded(0.5 * longest_step)
+    }
+}
+
+impl TriGeometry {
+    /// Builds the hierarchy over the `instances` of the `meshes`
+    pub fn new(meshes: Vec<TriangleMesh>, materials: Vec<PBRMaterial>, instances: Vec<Instance>) -> Self {
+        let first_triangles: Vec<usize> = instances
+            .iter()
+            .scan(0, |count, instance| {
+                let first = *count;
+                *count += meshes[instance.mesh].triangle_count();
+                Some(first)
+            })
+            .collect();
+        let triangle_count = instances.iter().map(|instance| meshes[instance.mesh].triangle_count()).sum();
+        let bounds: Vec<Aabb> = instances.iter().map(|instance| instance.bounds(&meshes[instance.mesh])).collect();
+        TriGeometry {
+            bvh: Bvh::build(&bounds),
+            meshes,
+            materials,
+            instances,
+            first_triangles,
+            triangle_count,
+        }
+    }
+
+    /// Triangles in the scene, every instance counts its mesh's
+    pub fn triangle_count(&self) -> usize {
+        self.triangle_count
+    }
+
+    pub fn has_motion(&self) -> bool {
+        self.instances
+            .iter()
+            .any(|instance| instance.motion.is_some() || self.meshes[instance.mesh].deforms())
+    }
+
+    /// Triangle `idx` in the world at shutter `time`, with the material of its instance
+    pub fn posed(&self, idx: usize, time: f64) -> Triangle {
+        let instance_idx = self.first_triangles.partition_point(|first| *first <= idx) - 1;
+        let instance = &self.instances[instance_idx];
+        let mesh = &self.meshes[instance.mesh];
+        let tri = self.triangle(instance, mesh, idx - self.first_triangles[instance_idx]);
+        let transform = instance.transform_at(time);
+        Triangle {
+            vertices: mesh.vertices_at(tri.idx, time).map(|v| transform_point(&transform, v)),
+            pbr_mat: *tri.material,
+        }
+    }
+
+    /// Triangle `idx` of `mesh` placed by `instance`, the instance's material replaces the mesh's
+    fn triangle<'a>(&'a self, instance: &Instance, mesh: &'a TriangleMesh, idx: usize) -> TriangleRef<'a> {
+        let material = instance.material.unwrap_or(mesh.materials[idx]);
+        TriangleRef {
+            mesh,
+            idx,
+            material: &self.materials[material as usize],
+        }
+    }
+
+    /// The ray in the space of the instance's mesh, the distances along it stay the same
+    fn to_object(ray: &Ray, inverse: &Matrix4<f64>, max: f64) -> Ray {
+        Ray {
+            pos: transform_point(inverse, ray.pos),
+            dir: transform_vector(inverse, ray.dir),
+            min: ray.min,
+            max,
+            time: ray.time,
+        }
+    }
+}
+
+impl Triangle {
+    pub fn area(&self) -> f64 {
+        let e_1 = self.vertices[1] - self.vertices[0];
+        let e_2 = self.vertices[2] - self.vertices[0];
+        0.5 * e_1.cross(e_2).length()
+    }
+
+    /// Uniformly distributed point on the triangle and its face normal
+    pub fn sample(&self, u: [f64; 2]) -> (Vec3, Vec3) {
+        let b = uniform_triangle(u);
+        let point = b[0] * self.vertices[0] + b[1] * self.vertices[1] + b[2] * self.vertices[2];
+        let e_1 = self.vertices[1] - self.vertices[0];
+        let e_2 = self.vertices[2] - self.vertices[0];
+        (point, e_1.cross(e_2).normalize())
+    }
+}
+
+/// Pink of the spheres, `[0xd3, 0x68, 0x7d]`
+const SPHERE_MATERIAL: PBRMaterial = PBRMaterial {
+    color: FColor { rgb: [0xd3 as f64 / 256., 0x68 as f64 / 256., 0x7d as f64 / 256.] },
+    metallic_factor: 0.0,
+    roughness: 1.0,
+    ior: 1.0,
+    transmissive: 0.0,
+    thickness: 0.0,
+    attenuation_color: FColor { rgb: [1.; 3] },
+    attenuation_distance: f64::INFINITY,
+    emissive: FColor::BLACK,
+    id: None,
+};
+
 impl Intersect for Sphere {
-    fn intersect(&self, ray: &Ray) -> IntersectionResult {
+    fn intersect(&self, ray: &Ray) -> IntersectionResult<'_> {
         let a = ray.dir.dotp(ray.dir); // D^2
         let b = 2.0 * ray.dir.dotp(ray.pos - self.position); // 2D(O-C)
 
@@ -59,7 +395,7 @@ impl Intersect for Sphere {
 
         let t = f64::min((-b + delta.sqrt()) / 2.0 * a, (-b - delta.sqrt()) / 2.0 * a);
 
-        const THRESHOLD: f64 = 0.03;
+        //const THRESHOLD: f64 = 0.03;
         match delta {
             // TODO: re-add functionality for edge detection
             /*x if x > -THRESHOLD && x < THRESHOLD && t > 0. => Hit(
@@ -73,12 +409,15 @@ impl Intersect for Sphere {
             x if x < -THRESHOLD => Miss, // hit in no points*/
             x if x > 0. && t > 0.0 => {
                 let point = ray.at(t);
+                let normal = (point - self.position).normalize();
                 Hit {
                     idx: 0,
                     point,
-                    normal: -(point - self.position).normalize(),
+                    normal,
+                    shading_normal: normal,
                     t,
-                    color: [0xd3, 0x68, 0x7d].into(),
+                    barycentric: [1., 0., 0.],
+                    material: &SPHERE_MATERIAL,
                 }
             }
             _ => Miss,
@@ -87,53 +426,66 @@ impl Intersect for Sphere {
 }
 
 impl Intersect for Triangle {
-    fn intersect(&self, ray: &Ray) -> IntersectionResult {
-        //let eps = 1e-4;
+    fn intersect(&self, ray: &Ray) -> IntersectionResult<'_> {
+        intersect_triangle(self.vertices, &self.pbr_mat, ray)
+    }
+}
 
-        // edge vectors
-        let e_1 = self.vertices[1] - self.vertices[0];
-        let e_2 = self.vertices[2] - self.vertices[0];
+impl<'a> Intersect for TriangleRef<'a> {
+    fn intersect(&self, ray: &Ray) -> IntersectionResult<'a> {
+        intersect_triangle(self.mesh.vertices_at(self.idx, ray.time), self.material, ray)
+    }
+}
 
-        // face normal
-        let n = e_1.cross(e_2).normalize();
-        let q = ray.dir.cross(e_2);
-        let a = e_1.dotp(q);
-
-        // Backfacing or nearly parallel?
-        if
-        /*(n.scalar_mul(ray.dir) >= 0.) ||*/
-        (a.abs() <= 1e-10) {
-            //print!("█");
-            return Miss;
-        }
+/// Hits the triangle with the corners `vertices` made of `material`
+fn intersect_triangle<'a>(vertices: [Vec3; 3], material: &'a PBRMaterial, ray: &Ray) -> IntersectionResult<'a> {
+    //let eps = 1e-4;
 
-        // Barycentric coordinates
-        let s = (ray.pos - self.vertices[0]) * (1. / a);
-        let r = s.cross(e_1);
+    // edge vectors
+    let e_1 = vertices[1] - vertices[0];
+    let e_2 = vertices[2] - vertices[0];
 
-        let mut b = [0.; 3];
-        b[0] = s.dotp(q);
-        b[1] = r.dotp(ray.dir);
-        b[2] = 1.0 - b[0] - b[1];
+    // face normal
+    let n = e_1.cross(e_2).normalize();
+    let q = ray.dir.cross(e_2);
+    let a = e_1.dotp(q);
 
-        // Intersected outside triangle?
-        if b.iter().any(|x| *x < 0. || *x > 1.) {
-            //print!("░");
-            return Miss;
-        }
-        let t = e_2.dotp(r);
-        match t >= 0. {
-            // Hit
-            true => Hit {
-                idx: 0,
-                point: ray.at(t),
-                normal: n,
-                t,
-                color: self.pbr_mat,
-            },
-            // Miss
-            false => Miss,
-        }
+    // Backfacing or nearly parallel?
+    if
+    /*(n.scalar_mul(ray.dir) >= 0.) ||*/
+    a.abs() <= 1e-10 {
+        //print!("█");
+        return Miss;
+    }
+
+    // Barycentric coordinates
+    let s = (ray.pos - vertices[0]) * (1. / a);
+    let r = s.cross(e_1);
+
+    let mut b = [0.; 3];
+    b[0] = s.dotp(q);
+    b[1] = r.dotp(ray.dir);
+    b[2] = 1.0 - b[0] - b[1];
+
+    // Intersected outside triangle?
+    if b.iter().any(|x| *x < 0. || *x > 1.) {
+        //print!("░");
+        return Miss;
+    }
+    let t = e_2.dotp(r);
+    match t >= 0. {
+        // Hit
+        true => Hit {
+            idx: 0,
+            point: ray.at(t),
+            normal: n,
+            shading_normal: n,
+            t,
+            barycentric: b,
+            material,
+        },
+        // Miss
+        false => Miss,
     }
 }
 /* If ray P + tw hits triangle V[0] , V[1] , V[2] , then the
@@ -177,42 +529,88 @@ undefined.*/
 } */
 
 impl Intersect for TriGeometry {
-    fn intersect(&self, ray: &Ray) -> IntersectionResult {
-        let res = self
-            .objects
-            .par_iter()
-            .enumerate()
-            .map(|(idx, obj)| (idx, obj.intersect(ray)))
-            .filter_map(|(obj_idx, r)| match r {
-                Hit {
-                    idx: _, // this is always 0, cuz one obj is scanned; ignore this idx then 
-                    point,
-                    normal,
-                    t,
-                    color,
-                } => Some((obj_idx, point, normal, t, color)),
-                Miss => None,
-            })
-            .filter(|(_idx, _p, _n, t, _color)| *t >= ray.min && *t <= ray.max)
-            .min_by(|a, b| {
-                let t_1 = a.3;
-                let t_2 = b.3;
-                t_1.total_cmp(&t_2)
-            });
+    fn intersect(&self, ray: &Ray) -> IntersectionResult<'_> {
+        let mut closest = Miss;
+        self.bvh.traverse(ray, |instance_idx, max| {
+            let instance = &self.instances[instance_idx];
+            let mesh = &self.meshes[instance.mesh];
+            let inverse = instance.inverse_at(ray.time);
+            let local = Self::to_object(ray, &inverse, max);
 
-        if let Some(_) = res {
-            //print!("#");
-        }
+            let mut nearest = None;
+            mesh.bvh.traverse(&local, |tri_idx, max| {
+                let material = self.triangle(instance, mesh, tri_idx).material;
+                match intersect_triangle(mesh.vertices_at(tri_idx, local.time), material, &local) {
+                    Hit { t, normal, barycentric, material, .. } if t >= local.min && t <= max => {
+                        nearest = Some((tri_idx, t, normal, barycentric, material));
+                        Some(t)
+                    }
+                    _ => None,
+                }
+            });
 
-        match res {
-            Some((idx, point, normal, t, color)) => Hit {
-                idx,
-                point,
+            let (tri_idx, t, normal, barycentric, material) = nearest?;
+            // normals go back into the world with the inverse transpose
+            let to_world = |n: Vec3| transform_vector(&inverse.transpose(), n).normalize();
+            let normal = to_world(normal);
+            closest = Hit {
+                idx: self.first_triangles[instance_idx] + tri_idx,
+                point: ray.at(t),
                 normal,
+                shading_normal: mesh.shading_normal(tri_idx, barycentric).map_or(normal, to_world),
                 t,
-                color,
-            },
-            None => Miss,
+                barycentric,
+                material,
+            };
+            Some(t)
+        });
+        closest
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        self.bvh.any(ray, |instance_idx| {
+            let instance = &self.instances[instance_idx];
+            let mesh = &self.meshes[instance.mesh];
+            let local = Self::to_object(ray, &instance.inverse_at(ray.time), ray.max);
+            mesh.bvh.any(&local, |tri_idx| self.triangle(instance, mesh, tri_idx).occluded(&local))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ray(pos: Vec3, dir: Vec3) -> Ray {
+        Ray { pos, dir, min: 0., max: f64::INFINITY, time: 0. }
+    }
+
+    #[test]
+    fn shading_normal_blends_the_normal_of_the_nearest_corner() {
+        let normals = vec![Vec3::new(0., 0., 1.), Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.)];
+        let positions = vec![Vec3::new(0., 0., 0.), Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.)];
+        let mesh = TriangleMesh::new(positions.clone(), Vec::new(), normals.clone(), vec![[0, 1, 2]], vec![0]);
+        let material = PBRMaterial::from([255, 255, 255]);
+
+        for (corner, normal) in positions.iter().zip(&normals) {
+            // just inside the corner
+            let target = 0.98 * *corner + 0.01 * (positions[0] + positions[1] + positions[2]);
+            let Hit { barycentric, .. } = intersect_triangle(
+                mesh.vertices_at(0, 0.),
+                &material,
+                &ray(target + Vec3::new(0., 0., 1.), Vec3::new(0., 0., -1.)),
+            ) else {
+                panic!("missed the triangle");
+            };
+            let shading_normal = mesh.shading_normal(0, barycentric).unwrap();
+            assert!(shading_normal.dotp(*normal) > 0.99, "{shading_normal:?} is not near {normal:?}");
         }
     }
+
+    #[test]
+    fn meshes_without_normals_shade_with_the_face() {
+        let positions = vec![Vec3::new(0., 0., 0.), Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.)];
+        let mesh = TriangleMesh::new(positions, Vec::new(), Vec::new(), vec![[0, 1, 2]], vec![0]);
+        assert!(mesh.shading_normal(0, [0.3, 0.3, 0.4]).is_none());
+    }
 }
@@ -0,0 +1,27 @@
+//! The rei ray-tracer, shared by the `rei` command line and its render service
+
+pub mod animation;
+pub mod aov;
+pub mod bvh;
+pub mod camera;
+pub mod checkpoint;
+pub mod deform;
+pub mod denoise;
+pub mod distributed;
+pub mod environment;
+pub mod framebuffer;
+pub mod integrator;
+pub mod intersections;
+pub mod light_transport;
+pub mod maths;
+pub mod model;
+pub mod motion;
+pub mod path_tracer;
+pub mod ray;
+pub mod render;
+pub mod renderer;
+pub mod sampling;
+pub mod scene;
+pub mod settings;
+pub mod sky;
+pub mod whitted;
@@ -1,6 +1,12 @@
-use std::ops::{Mul, Add, AddAssign};
+use std::ops::{Mul, Add, AddAssign, Sub};
 
-use crate::{maths::Vec3, ray::Ray};
+use crate::{
+    environment::EnvironmentLight,
+    maths::{Frame, Vec3},
+    ray::Ray,
+    sky::SkyLight,
+    sampling::{concentric_disk, cosine_hemisphere, uniform_cone, uniform_sphere},
+};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Color {
@@ -10,7 +16,7 @@ pub struct Color {
     pub a: u8,
 }
 impl Color {
-    pub fn to_fcolor(&self) -> FColor {
+    pub fn to_fcolor(self) -> FColor {
        let arr = [self.r, self.g, self.b];
        FColor { rgb: arr.map(|x| (x as f64) / 256. )
      } 
@@ -88,10 +94,21 @@ pub struct FColor {
 }
 
 impl FColor {
+    pub const BLACK: FColor = FColor { rgb: [0., 0., 0.] };
+
     pub fn to_color(self) -> Color {
         let arr = self.rgb.map(|x| (x * 256.) as u8);
         Color::from(arr)
     }
+
+    pub fn is_black(&self) -> bool {
+        self.rgb.iter().all(|x| *x <= 0.)
+    }
+
+    /// Rec. 709 relative luminance
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.rgb[0] + 0.7152 * self.rgb[1] + 0.0722 * self.rgb[2]
+    }
 }
 
 impl From<[f64; 3]> for FColor {
@@ -133,6 +150,18 @@ impl Add<FColor> for FColor {
     }
 }
 
+impl Sub<FColor> for FColor {
+    type Output = FColor;
+
+    fn sub(self, rhs: FColor) -> Self::Output {
+        FColor { rgb: [
+            self.rgb[0] - rhs.rgb[0],
+            self.rgb[1] - rhs.rgb[1],
+            self.rgb[2] - rhs.rgb[2],
+        ] }
+    }
+}
+
 impl AddAssign for FColor {
     fn add_assign(&mut self, rhs: Self) {
         *self = *self + rhs;
@@ -143,15 +172,480 @@ impl AddAssign for FColor {
 pub struct PBRMaterial {
     pub color: FColor,
     //specular_exponent: f64,
-    pub metallic_factor: f64, 
+    pub metallic_factor: f64,
+    /// perceptual roughness, squared for the microfacet distribution
+    pub roughness: f64,
     pub ior: f64,
-    pub transmissive: f64
+    /// KHR_materials_transmission factor
+    pub transmissive: f64,
+    /// KHR_materials_volume thickness, zero for thin-walled surfaces without a volume
+    pub thickness: f64,
+    /// color white light turns into after travelling `attenuation_distance` through the volume
+    pub attenuation_color: FColor,
+    pub attenuation_distance: f64,
+    /// emissiveFactor scaled by KHR_materials_emissive_strength
+    pub emissive: FColor,
+    /// index of the glTF material, None for the default material
+    pub id: Option<usize>,
 }
 
 impl From<[u8; 3]> for PBRMaterial {
     fn from(value: [u8; 3]) -> Self {
-        PBRMaterial { color: Color::from(value).to_fcolor(), metallic_factor: 0.0, ior: 1.0, transmissive: 0.0 }
+        PBRMaterial { color: Color::from(value).to_fcolor(), metallic_factor: 0.0, roughness: 1.0, ior: 1.0, transmissive: 0.0, thickness: 0.0, attenuation_color: FColor::from([1., 1., 1.]), attenuation_distance: f64::INFINITY, emissive: FColor::BLACK, id: None }
+    }
+}
+
+impl PBRMaterial {
+    pub fn is_emissive(&self) -> bool {
+        !self.emissive.is_black()
+    }
+
+    pub fn is_transmissive(&self) -> bool {
+        self.transmissive > 0.
+    }
+
+    /// Share of the light left after travelling `distance` through the volume (Beer-Lambert law).
+    /// With sigma = -ln(attenuation_color) / attenuation_distance, exp(-sigma * distance) becomes a power.
+    pub fn volume_transmittance(&self, distance: f64) -> FColor {
+        if self.thickness <= 0. || !self.attenuation_distance.is_finite() {
+            return FColor::from([1., 1., 1.]);
+        }
+        let exponent = distance / self.attenuation_distance;
+        FColor {
+            rgb: self.attenuation_color.rgb.map(|c| c.clamp(0., 1.).powf(exponent)),
+        }
+    }
+
+    /// Light left along a ray in direction `dir` when it reaches the surface with the outside `normal`
+    /// after `distance`. Reaching a surface from its inside means the ray crossed its volume.
+    pub fn transmittance_to(&self, dir: Vec3, normal: Vec3, distance: f64) -> FColor {
+        if dir.dotp(normal) > 0. {
+            self.volume_transmittance(distance)
+        } else {
+            FColor::from([1., 1., 1.])
+        }
+    }
+
+    /// Shading frame at a surface with the outside geometric `normal` and the interpolated `shading_normal`,
+    /// seen from `view_dir`. It is built around the shading normal on the side of the viewer; glass keeps
+    /// the outside geometric normal to tell exactly whether the ray enters or leaves it.
+    pub fn shading_frame(&self, normal: Vec3, shading_normal: Vec3, view_dir: Vec3) -> Frame {
+        if self.is_transmissive() {
+            return Frame::new(normal);
+        }
+        // interpolated normals may lean over to the other side of the face
+        let shading_normal = if shading_normal.dotp(normal) < 0. { -shading_normal } else { shading_normal };
+        Frame::new(if normal.dotp(view_dir) < 0. { -shading_normal } else { shading_normal })
+    }
+
+    /// The scattering model best matching the material
+    pub fn bsdf(&self) -> Box<dyn Bsdf> {
+        if self.is_transmissive() {
+            Box::new(Dielectric {
+                ior: self.ior,
+                transmission: self.transmissive.min(1.),
+                tint: self.color,
+            })
+        } else if self.metallic_factor >= 1. && self.roughness <= 0. {
+            Box::new(Conductor {
+                f0: self.color,
+            })
+        } else {
+            Box::new(MicrofacetBrdf::new(self.color, self.metallic_factor, self.roughness))
+        }
     }
+
+    /// Share of the light a surface mirrors towards the viewer, for Whitted-style reflections.
+    /// Rough surfaces blur the mirror image away, their highlights come from light sampling instead.
+    pub fn mirror_reflectance(&self, cos_o: f64) -> FColor {
+        let smoothness = (1. - self.roughness).clamp(0., 1.);
+        MicrofacetBrdf::new(self.color, self.metallic_factor, self.roughness).fresnel(cos_o)
+            * (smoothness * smoothness)
+    }
+}
+
+/// Direction sampled from a BSDF
+pub struct BsdfSample {
+    /// direction the light arrives from, in the local shading frame
+    pub wi: Vec3,
+    /// BSDF value for `wi`
+    pub f: FColor,
+    /// solid angle pdf of `wi`; for delta distributions the probability of picking this lobe
+    pub pdf: f64,
+    /// perfectly specular, `eval` and `pdf` are zero for every direction
+    pub delta: bool,
+}
+
+/// Scattering at a surface point.
+/// Directions live in the local shading frame (normal is +z) and point away from the surface.
+pub trait Bsdf {
+    fn eval(&self, wo: Vec3, wi: Vec3) -> FColor;
+
+    /// Samples an incoming direction for the outgoing one, `u` is uniform in [0, 1)^2
+    fn sample(&self, wo: Vec3, u: [f64; 2]) -> Option<BsdfSample>;
+
+    /// Solid angle pdf `sample` picks `wi` with
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f64;
+
+    /// The part of `eval` scattered diffusely, the rest counts as specular
+    fn eval_diffuse(&self, _wo: Vec3, _wi: Vec3) -> FColor {
+        FColor::BLACK
+    }
+
+    /// Every perfectly specular direction together with the share of light it carries (f * cos / pdf),
+    /// so Whitted-style tracing can follow all of them instead of picking one
+    fn specular_directions(&self, _wo: Vec3) -> Vec<(Vec3, FColor)> {
+        Vec::new()
+    }
+}
+
+fn same_hemisphere(a: Vec3, b: Vec3) -> bool {
+    a.z() * b.z() > 0.
+}
+
+fn mirror(wo: Vec3) -> Vec3 {
+    Vec3([-wo.x(), -wo.y(), wo.z()])
+}
+
+/// Ideal diffuse reflection
+pub struct Lambertian {
+    pub albedo: FColor,
+}
+
+impl Bsdf for Lambertian {
+    fn eval(&self, wo: Vec3, wi: Vec3) -> FColor {
+        if wo.z() <= 0. || wi.z() <= 0. {
+            return FColor::BLACK;
+        }
+        self.albedo * std::f64::consts::FRAC_1_PI
+    }
+
+    fn sample(&self, wo: Vec3, u: [f64; 2]) -> Option<BsdfSample> {
+        if wo.z() <= 0. {
+            return None;
+        }
+        let wi = cosine_hemisphere(u);
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi),
+            pdf: self.pdf(wo, wi),
+            delta: false,
+        })
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        if wo.z() <= 0. || wi.z() <= 0. {
+            return 0.;
+        }
+        wi.z() * std::f64::consts::FRAC_1_PI
+    }
+
+    fn eval_diffuse(&self, wo: Vec3, wi: Vec3) -> FColor {
+        self.eval(wo, wi)
+    }
+}
+
+/// Perfectly smooth metal
+pub struct Conductor {
+    /// reflectance at normal incidence
+    pub f0: FColor,
+}
+
+impl Bsdf for Conductor {
+    fn eval(&self, _wo: Vec3, _wi: Vec3) -> FColor {
+        FColor::BLACK
+    }
+
+    fn sample(&self, wo: Vec3, _u: [f64; 2]) -> Option<BsdfSample> {
+        if wo.z() <= 0. {
+            return None;
+        }
+        let wi = mirror(wo);
+        Some(BsdfSample {
+            wi,
+            f: schlick(self.f0, wo.z()) * (1. / wi.z()),
+            pdf: 1.,
+            delta: true,
+        })
+    }
+
+    fn pdf(&self, _wo: Vec3, _wi: Vec3) -> f64 {
+        0.
+    }
+
+    fn specular_directions(&self, wo: Vec3) -> Vec<(Vec3, FColor)> {
+        if wo.z() <= 0. {
+            return Vec::new();
+        }
+        vec![(mirror(wo), schlick(self.f0, wo.z()))]
+    }
+}
+
+/// Smooth glass after KHR_materials_transmission: the Fresnel reflection sits on top, of the rest
+/// `transmission` is refracted (tinted by the base color) and the remainder reflected diffusely.
+/// The shading frame normal has to point outside: `wo.z() < 0` means the ray comes from inside.
+pub struct Dielectric {
+    /// index of refraction inside, outside is air
+    pub ior: f64,
+    /// share of the light passing the surface instead of being diffusely reflected
+    pub transmission: f64,
+    pub tint: FColor,
+}
+
+impl Dielectric {
+    fn fresnel(&self, wo: Vec3) -> f64 {
+        fresnel_dielectric(wo.z(), IOR_AIR, self.ior)
+    }
+
+    fn diffuse(&self) -> Lambertian {
+        Lambertian {
+            albedo: self.tint * (1. - self.transmission),
+        }
+    }
+
+    /// Share of the light not reflected that gets refracted, from inside there is no diffuse part
+    fn transmission_share(&self, wo: Vec3) -> f64 {
+        if wo.z() > 0. { self.transmission } else { 1. }
+    }
+
+    /// Refracted direction and the share of light it carries, None on total internal reflection
+    fn transmitted(&self, wo: Vec3) -> Option<(Vec3, FColor)> {
+        let entering = wo.z() > 0.;
+        let (eta_i, eta_t) = if entering { (IOR_AIR, self.ior) } else { (self.ior, IOR_AIR) };
+        let normal = Vec3([0., 0., if entering { 1. } else { -1. }]);
+        let wi = refract(wo, normal, eta_i / eta_t)?;
+
+        // radiance gets compressed into the smaller solid angle of the denser medium
+        let transmittance = (1. - self.fresnel(wo)) * (eta_i * eta_i) / (eta_t * eta_t);
+        let tint = if entering { self.tint * self.transmission } else { FColor::from([1., 1., 1.]) };
+        Some((wi, tint * transmittance))
+    }
+}
+
+impl Bsdf for Dielectric {
+    fn eval(&self, wo: Vec3, wi: Vec3) -> FColor {
+        self.diffuse().eval(wo, wi) * (1. - self.fresnel(wo))
+    }
+
+    fn sample(&self, wo: Vec3, u: [f64; 2]) -> Option<BsdfSample> {
+        let fresnel = self.fresnel(wo);
+
+        if u[0] < fresnel {
+            let wi = mirror(wo);
+            return Some(BsdfSample {
+                wi,
+                f: FColor::from([fresnel; 3]) * (1. / wi.z().abs()),
+                pdf: fresnel,
+                delta: true,
+            });
+        }
+
+        // total internal reflection is covered by fresnel == 1
+        let refract_below = fresnel + (1. - fresnel) * self.transmission_share(wo);
+        if u[0] < refract_below {
+            let (wi, transmittance) = self.transmitted(wo)?;
+            return Some(BsdfSample {
+                wi,
+                f: transmittance * (1. / wi.z().abs()),
+                pdf: refract_below - fresnel,
+                delta: true,
+            });
+        }
+
+        let u = [(u[0] - refract_below) / (1. - refract_below), u[1]];
+        let wi = self.diffuse().sample(wo, u)?.wi;
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi),
+            pdf: self.pdf(wo, wi),
+            delta: false,
+        })
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        let diffuse_probability = (1. - self.fresnel(wo)) * (1. - self.transmission_share(wo));
+        self.diffuse().pdf(wo, wi) * diffuse_probability
+    }
+
+    fn eval_diffuse(&self, wo: Vec3, wi: Vec3) -> FColor {
+        self.eval(wo, wi)
+    }
+
+    fn specular_directions(&self, wo: Vec3) -> Vec<(Vec3, FColor)> {
+        let mut directions = vec![(mirror(wo), FColor::from([self.fresnel(wo); 3]))];
+        directions.extend(self.transmitted(wo));
+        directions
+    }
+}
+
+/// Refracts `wo` (pointing away from the surface) through the interface with normal `normal` on the side of `wo`,
+/// `eta` is the ratio of the ior on the side of `wo` to the other side. None on total internal reflection.
+pub fn refract(wo: Vec3, normal: Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = normal.dotp(wo);
+    let sin2_i = f64::max(0., 1. - cos_i * cos_i);
+    let sin2_t = eta * eta * sin2_i;
+    if sin2_t >= 1. {
+        return None;
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    Some(eta * -wo + (eta * cos_i - cos_t) * normal)
+}
+
+/// Exact Fresnel reflectance of unpolarized light between two dielectrics.
+/// `cos_i` is measured against the normal pointing into the medium with `eta_i`, negative means from inside.
+pub fn fresnel_dielectric(cos_i: f64, eta_i: f64, eta_t: f64) -> f64 {
+    let mut cos_i = cos_i.clamp(-1., 1.);
+    let (eta_i, eta_t) = if cos_i > 0. {
+        (eta_i, eta_t)
+    } else {
+        cos_i = -cos_i;
+        (eta_t, eta_i)
+    };
+
+    let sin_i = f64::max(0., 1. - cos_i * cos_i).sqrt();
+    let sin_t = eta_i / eta_t * sin_i;
+    if sin_t >= 1. {
+        return 1.; // total internal reflection
+    }
+    let cos_t = f64::max(0., 1. - sin_t * sin_t).sqrt();
+
+    let r_parallel = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let r_perpendicular = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.
+}
+
+/// Schlick's approximation of the Fresnel reflectance
+fn schlick(f0: FColor, cos_theta: f64) -> FColor {
+    let weight = (1. - cos_theta.clamp(0., 1.)).powi(5);
+    f0 + (FColor::from([1., 1., 1.]) - f0) * weight
+}
+
+/// Reflectance of dielectrics at normal incidence (ior 1.5)
+const DIELECTRIC_F0: f64 = 0.04;
+
+/// glTF metallic-roughness BRDF: GGX specular lobe over a Lambertian base
+// https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html#appendix-b-brdf-implementation
+pub struct MicrofacetBrdf {
+    /// Lambertian base, black for metals
+    diffuse: Lambertian,
+    /// specular reflectance at normal incidence
+    f0: FColor,
+    /// GGX alpha, roughness squared
+    alpha: f64,
+}
+
+impl MicrofacetBrdf {
+    pub fn new(base_color: FColor, metallic: f64, roughness: f64) -> Self {
+        let metallic = metallic.clamp(0., 1.);
+        let dielectric = FColor::from([DIELECTRIC_F0; 3]);
+        MicrofacetBrdf {
+            diffuse: Lambertian {
+                albedo: base_color * (1. - metallic),
+            },
+            f0: dielectric * (1. - metallic) + base_color * metallic,
+            // perfectly smooth surfaces would make the distribution a delta
+            alpha: f64::max(roughness * roughness, 1e-3),
+        }
+    }
+
+    pub fn fresnel(&self, cos_theta: f64) -> FColor {
+        schlick(self.f0, cos_theta)
+    }
+
+    /// Probability of sampling the specular lobe, by how much light each lobe reflects
+    fn specular_probability(&self, wo: Vec3) -> f64 {
+        let specular = self.fresnel(wo.z()).luminance();
+        let diffuse = self.diffuse.albedo.luminance();
+        if specular + diffuse <= 0. {
+            return 1.;
+        }
+        (specular / (specular + diffuse)).clamp(0.1, 1.)
+    }
+
+    fn specular_pdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        let h = (wo + wi).normalize();
+        // pdf of the half vector, converted to the reflected direction
+        ggx_distribution(h.z(), self.alpha) * h.z() / (4. * wo.dotp(h).abs())
+    }
+}
+
+impl Bsdf for MicrofacetBrdf {
+    fn eval(&self, wo: Vec3, wi: Vec3) -> FColor {
+        let cos_o = wo.z();
+        let cos_i = wi.z();
+        if cos_o <= 0. || cos_i <= 0. {
+            return FColor::BLACK;
+        }
+
+        let h = (wo + wi).normalize();
+        let f = self.fresnel(wo.dotp(h));
+        let specular = f * (ggx_distribution(h.z(), self.alpha) * smith_visibility(cos_o, cos_i, self.alpha));
+        let diffuse = (FColor::from([1., 1., 1.]) - f) * self.diffuse.eval(wo, wi);
+
+        specular + diffuse
+    }
+
+    fn eval_diffuse(&self, wo: Vec3, wi: Vec3) -> FColor {
+        if wo.z() <= 0. || wi.z() <= 0. {
+            return FColor::BLACK;
+        }
+        let h = (wo + wi).normalize();
+        (FColor::from([1., 1., 1.]) - self.fresnel(wo.dotp(h))) * self.diffuse.eval(wo, wi)
+    }
+
+    fn sample(&self, wo: Vec3, u: [f64; 2]) -> Option<BsdfSample> {
+        if wo.z() <= 0. {
+            return None;
+        }
+
+        let p_specular = self.specular_probability(wo);
+        let wi = if u[0] < p_specular {
+            // half vector distributed by D(h) cos(h)
+            let u0 = u[0] / p_specular;
+            let tan2_theta = self.alpha * self.alpha * u0 / (1. - u0);
+            let cos_theta = 1. / (1. + tan2_theta).sqrt();
+            let sin_theta = f64::max(0., 1. - cos_theta * cos_theta).sqrt();
+            let phi = 2. * std::f64::consts::PI * u[1];
+            let h = Vec3([sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta]);
+            2. * wo.dotp(h) * h - wo
+        } else {
+            let u0 = (u[0] - p_specular) / (1. - p_specular);
+            cosine_hemisphere([u0, u[1]])
+        };
+
+        if wi.z() <= 0. {
+            return None;
+        }
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi),
+            pdf: self.pdf(wo, wi),
+            delta: false,
+        })
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        if !same_hemisphere(wo, wi) || wo.z() <= 0. {
+            return 0.;
+        }
+        let p_specular = self.specular_probability(wo);
+        p_specular * self.specular_pdf(wo, wi) + (1. - p_specular) * self.diffuse.pdf(wo, wi)
+    }
+}
+
+/// Trowbridge-Reitz (GGX) normal distribution
+fn ggx_distribution(cos_h: f64, alpha: f64) -> f64 {
+    let a2 = alpha * alpha;
+    let d = cos_h * cos_h * (a2 - 1.) + 1.;
+    a2 / (std::f64::consts::PI * d * d)
+}
+
+/// Height-correlated Smith masking-shadowing, divided by 4 cos_o cos_i
+fn smith_visibility(cos_o: f64, cos_i: f64, alpha: f64) -> f64 {
+    let a2 = alpha * alpha;
+    let view = cos_i * (cos_o * cos_o * (1. - a2) + a2).sqrt();
+    let light = cos_o * (cos_i * cos_i * (1. - a2) + a2).sqrt();
+    0.5 / (view + light)
 }
 
 // ISBN: 978-1-4842-7185-8 page 105
@@ -162,36 +656,12 @@ pub fn reflect_light(incoming: &Ray, normal: Vec3, point: Vec3) -> Ray {
         dir,
         min: incoming.min,
         max: incoming.max,
+        time: incoming.time,
     }
 }
 
 const IOR_AIR: f64 = 1.; // roughly that shit
 
-// ISBN: 978-1-4842-7185-8 page 106 onwards
-pub fn refract_light(
-    incoming: &Ray,
-    normal: Vec3,
-    point: Vec3,
-    ior: f64
-) -> Option<Ray> {
-    let eta = ior; // relative ior
-    let c1 = -incoming.dir.dotp(normal); // cos( index of reflection)
-    let w = eta * c1;
-    let c2m = (w - eta) * (w + eta); // cos^2 (outgoing iof) - 1
-
-    if c2m < -1.0 {
-        None // total internal reflecion
-    } else {
-        let dir = eta * incoming.dir + (w - (1. + c2m).sqrt()) * normal;
-        Some(Ray {
-            pos: point,
-            dir,
-            min: incoming.min,
-            max: incoming.max,
-        })
-    }
-}
-
 /* pub fn light_source_from(
     incoming: &Ray,
     point: Vec3,
@@ -202,10 +672,345 @@ pub fn refract_light(
     Ray { pos: point, dir: light_dir, min: 0., max: f64::INFINITY }
 } */
 
-// TODO: emmisive
+/// Luminous efficacy used to turn the photometric glTF units (candela, lux) into radiometric ones
+pub const LUMENS_PER_WATT: f64 = 683.;
 
+#[derive(Debug)]
 pub struct PointLight {
     pub point: Vec3,
-    pub color: Color,
+    pub color: FColor,
+    /// radiant intensity (W/sr)
+    pub intensity: f64,
+    /// distance at which the light reaches zero, `None` means infinite
+    pub range: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct SpotLight {
+    pub point: Vec3,
+    /// direction the spot is pointing at
+    pub direction: Vec3,
+    pub color: FColor,
+    /// radiant intensity inside the inner cone (W/sr)
+    pub intensity: f64,
+    pub range: Option<f64>,
+    /// radians, from the spot direction to where the falloff begins
+    pub inner_cone_angle: f64,
+    /// radians, from the spot direction to where the falloff ends
+    pub outer_cone_angle: f64,
+}
+
+#[derive(Debug)]
+pub struct DirectionalLight {
+    /// direction the light is travelling in
+    pub direction: Vec3,
+    pub color: FColor,
+    /// irradiance (W/m^2)
+    pub intensity: f64,
+}
+
+/// Parallelogram spanned by two edges, emitting to the side of `edge_u x edge_v`
+#[derive(Debug)]
+pub struct RectLight {
+    pub corner: Vec3,
+    pub edge_u: Vec3,
+    pub edge_v: Vec3,
+    pub color: FColor,
+    /// emitted radiance (W/(sr m^2))
+    pub intensity: f64,
+}
+
+/// Disk emitting to the side its normal points to
+#[derive(Debug)]
+pub struct DiskLight {
+    pub center: Vec3,
+    pub normal: Vec3,
+    pub radius: f64,
+    pub color: FColor,
+    /// emitted radiance (W/(sr m^2))
+    pub intensity: f64,
+}
+
+#[derive(Debug)]
+pub struct SphereLight {
+    pub center: Vec3,
+    pub radius: f64,
+    pub color: FColor,
+    /// emitted radiance (W/(sr m^2))
     pub intensity: f64,
 }
+
+/// Every kind of light `shade_with` knows how to shade with
+pub enum Light {
+    Point(PointLight),
+    Spot(SpotLight),
+    Directional(DirectionalLight),
+    Rect(RectLight),
+    Disk(DiskLight),
+    Sphere(SphereLight),
+    Environment(Box<EnvironmentLight>),
+    Sky(Box<SkyLight>),
+}
+
+/// Light arriving at a point from a light source
+pub struct LightSample {
+    /// normalized direction from the point to the light
+    pub dir: Vec3,
+    /// distance to the light, infinite for directional lights
+    pub distance: f64,
+    /// irradiance arriving at the point (perpendicular to `dir`),
+    /// for area lights already divided by the probability of the sampled direction
+    pub radiance: FColor,
+}
+
+impl Light {
+    /// Lights at infinity, seen by rays leaving the scene
+    pub fn is_infinite(&self) -> bool {
+        matches!(self, Light::Environment(_) | Light::Sky(_))
+    }
+
+    /// Punctual lights are hit with a single shadow ray, area lights need several
+    pub fn is_delta(&self) -> bool {
+        matches!(self, Light::Point(_) | Light::Spot(_) | Light::Directional(_))
+    }
+
+    /// Samples light arriving at `point`, `u` picks the position on area lights
+    pub fn illuminate(&self, point: Vec3, u: [f64; 2]) -> LightSample {
+        match self {
+            Light::Point(light) => {
+                let distance = (light.point - point).length();
+                LightSample {
+                    dir: (light.point - point).normalize(),
+                    distance,
+                    radiance: light.color * (light.intensity * distance_falloff(distance, light.range)),
+                }
+            }
+            Light::Spot(light) => {
+                let distance = (light.point - point).length();
+                let dir = (light.point - point).normalize();
+
+                // smooth falloff from the inner to the outer cone
+                let cos_outer = light.outer_cone_angle.cos();
+                let cos_inner = light.inner_cone_angle.cos();
+                let cd = light.direction.normalize().dotp(-dir);
+                let cone = smoothstep(cos_outer, cos_inner, cd);
+
+                LightSample {
+                    dir,
+                    distance,
+                    radiance: light.color
+                        * (light.intensity * cone * distance_falloff(distance, light.range)),
+                }
+            }
+            Light::Directional(light) => LightSample {
+                dir: -light.direction.normalize(),
+                distance: f64::INFINITY,
+                radiance: light.color * light.intensity,
+            },
+            Light::Rect(light) => {
+                let light_point = light.corner + u[0] * light.edge_u + u[1] * light.edge_v;
+                let normal = light.edge_u.cross(light.edge_v);
+                let area = normal.length();
+                area_sample(point, light_point, normal.normalize(), area, light.color * light.intensity)
+            }
+            Light::Disk(light) => {
+                let [x, y] = concentric_disk(u);
+                let (tangent, bitangent) = light.normal.orthonormal_basis();
+                let light_point = light.center + light.radius * (x * tangent + y * bitangent);
+                let area = std::f64::consts::PI * light.radius * light.radius;
+                area_sample(point, light_point, light.normal.normalize(), area, light.color * light.intensity)
+            }
+            Light::Sphere(light) => sphere_sample(light, point, u),
+            Light::Environment(light) => light.sample(u),
+            Light::Sky(light) => light.sample(u),
+        }
+    }
+
+    /// Solid angle pdf `illuminate` picks `dir` with, for lights at infinity.
+    /// Other lights cannot be reached by rays leaving a surface and report zero.
+    pub fn pdf(&self, dir: Vec3) -> f64 {
+        match self {
+            Light::Environment(light) => light.pdf(dir),
+            Light::Sky(light) => light.pdf(dir),
+            _ => 0.,
+        }
+    }
+
+    /// Radiance of lights at infinity that a ray escaping the scene in direction `dir` sees
+    pub fn emitted(&self, dir: Vec3) -> FColor {
+        match self {
+            Light::Environment(light) => light.radiance(dir),
+            Light::Sky(light) => light.radiance(dir),
+            _ => FColor::BLACK,
+        }
+    }
+}
+
+/// Turns a uniformly sampled point on an one-sided area light into a light sample
+fn area_sample(point: Vec3, light_point: Vec3, light_normal: Vec3, area: f64, emitted: FColor) -> LightSample {
+    let distance = (light_point - point).length();
+    let dir = (light_point - point).normalize();
+    let cos_light = light_normal.dotp(-dir);
+
+    // area pdf (1 / area) to solid angle: dist^2 / cos at the light
+    let radiance = if cos_light > 0. && distance > 0. {
+        emitted * (cos_light * area / (distance * distance))
+    } else {
+        FColor::BLACK
+    };
+
+    LightSample {
+        dir,
+        distance,
+        radiance,
+    }
+}
+
+/// Samples the cone of directions the sphere covers as seen from `point` (pbrt, 3rd ed., 14.2.2)
+fn sphere_sample(light: &SphereLight, point: Vec3, u: [f64; 2]) -> LightSample {
+    let emitted = light.color * light.intensity;
+    let to_center = light.center - point;
+    let dc = to_center.length();
+
+    // inside the sphere every direction hits it: sample the surface uniformly
+    if dc <= light.radius {
+        let light_point = light.center + light.radius * uniform_sphere(u);
+        let distance = (light_point - point).length();
+        let dir = (light_point - point).normalize();
+        let cos_light = (light_point - light.center).normalize().dotp(dir).abs();
+        let area = 4. * std::f64::consts::PI * light.radius * light.radius;
+        return LightSample {
+            dir,
+            distance,
+            radiance: emitted * (cos_light * area / f64::max(distance * distance, 1e-8)),
+        };
+    }
+
+    let sin_theta_max2 = light.radius * light.radius / (dc * dc);
+    let cos_theta_max = f64::max(0., 1. - sin_theta_max2).sqrt();
+    let (cos_theta, phi) = uniform_cone(u, cos_theta_max);
+    let sin_theta = f64::max(0., 1. - cos_theta * cos_theta).sqrt();
+
+    let wc = to_center.normalize();
+    let (tangent, bitangent) = wc.orthonormal_basis();
+    let dir = (sin_theta * phi.cos()) * tangent + (sin_theta * phi.sin()) * bitangent + cos_theta * wc;
+
+    // distance to the first intersection with the sphere along dir
+    let distance = dc * cos_theta
+        - f64::max(0., light.radius * light.radius - dc * dc * sin_theta * sin_theta).sqrt();
+
+    let pdf = 1. / (2. * std::f64::consts::PI * (1. - cos_theta_max));
+
+    LightSample {
+        dir: dir.normalize(),
+        distance,
+        radiance: emitted * (1. / pdf),
+    }
+}
+
+/// Hermite interpolation between 0 at `edge0` and 1 at `edge1`
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    if edge1 <= edge0 {
+        return if x >= edge1 { 1. } else { 0. };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
+/// Inverse-square falloff, windowed to reach zero at `range`
+// https://github.com/KhronosGroup/glTF/tree/main/extensions/2.0/Khronos/KHR_lights_punctual#range-property
+fn distance_falloff(distance: f64, range: Option<f64>) -> f64 {
+    let inverse_square = 1. / f64::max(distance * distance, 1e-8);
+    match range {
+        Some(range) if range > 0. => {
+            let window = (1. - (distance / range).powi(4)).clamp(0., 1.);
+            window * window * inverse_square
+        }
+        _ => inverse_square,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::Sampler;
+
+    fn glass(transmission: f64) -> Dielectric {
+        Dielectric {
+            ior: 1.5,
+            transmission,
+            tint: FColor::from([0.9, 0.8, 0.7]),
+        }
+    }
+
+    /// Directions from straight on to grazing, outside and inside
+    fn outgoing() -> Vec<Vec3> {
+        [0.99, 0.7, 0.3, 0.05, -0.05, -0.3, -0.7, -0.99]
+            .into_iter()
+            .map(|z: f64| Vec3::new((1. - z * z).sqrt(), 0., z))
+            .collect()
+    }
+
+    #[test]
+    fn fresnel_dielectric_limits() {
+        // ((n - 1) / (n + 1))^2 at normal incidence, from either side
+        assert!((fresnel_dielectric(1., IOR_AIR, 1.5) - 0.04).abs() < 1e-12);
+        assert!((fresnel_dielectric(-1., IOR_AIR, 1.5) - 0.04).abs() < 1e-12);
+        assert!(fresnel_dielectric(1e-6, IOR_AIR, 1.5) > 0.99);
+        // past the critical angle of about 41.8° everything is reflected inside the glass
+        assert_eq!(fresnel_dielectric(-0.7, IOR_AIR, 1.5), 1.);
+        assert!(fresnel_dielectric(-0.8, IOR_AIR, 1.5) < 1.);
+    }
+
+    #[test]
+    fn refraction_follows_snell() {
+        for wo in outgoing() {
+            let entering = wo.z() > 0.;
+            let (eta_i, eta_t) = if entering { (IOR_AIR, 1.5) } else { (1.5, IOR_AIR) };
+            let normal = Vec3::new(0., 0., if entering { 1. } else { -1. });
+            match refract(wo, normal, eta_i / eta_t) {
+                Some(wi) => {
+                    let sin = |w: Vec3| (1. - w.z() * w.z()).sqrt();
+                    assert!((eta_i * sin(wo) - eta_t * sin(wi)).abs() < 1e-9, "{wo:?} to {wi:?}");
+                    assert!(wi.z() * wo.z() < 0., "{wi:?} stays on the side of {wo:?}");
+                }
+                None => assert_eq!(fresnel_dielectric(wo.z(), IOR_AIR, 1.5), 1.),
+            }
+        }
+    }
+
+    #[test]
+    fn dielectric_samples_agree_with_eval_pdf_and_specular_directions() {
+        let mut sampler = Sampler::new(5);
+        for transmission in [0., 0.5, 1.] {
+            let bsdf = glass(transmission);
+            for wo in outgoing() {
+                let specular = bsdf.specular_directions(wo);
+                let (mut delta_probability, mut samples) = (0., 0);
+                for _ in 0..2000 {
+                    let Some(sample) = bsdf.sample(wo, sampler.next_2d()) else {
+                        continue;
+                    };
+                    samples += 1;
+                    if sample.delta {
+                        // f * cos of a sampled delta direction is the share of light Whitted follows it with
+                        let (_, weight) = specular
+                            .iter()
+                            .find(|(wi, _)| (*wi - sample.wi).length() < 1e-9)
+                            .expect("a delta sample Whitted does not follow");
+                        let sampled = sample.f * sample.wi.z().abs();
+                        assert!((0..3).all(|c| (sampled.rgb[c] - weight.rgb[c]).abs() < 1e-9), "{wo:?}");
+                        delta_probability += 1.;
+                    } else {
+                        assert!((sample.pdf - bsdf.pdf(wo, sample.wi)).abs() < 1e-12, "{wo:?}");
+                        assert!((0..3).all(|c| sample.f.rgb[c] == bsdf.eval(wo, sample.wi).rgb[c]), "{wo:?}");
+                    }
+                }
+                // the diffuse lobe is picked as often as its pdf says, the pdf integrates to its probability
+                let diffuse_probability = (1. - bsdf.fresnel(wo)) * (1. - bsdf.transmission_share(wo));
+                let picked = 1. - delta_probability / samples as f64;
+                assert!((picked - diffuse_probability).abs() < 0.05, "{wo:?}: {picked} vs {diffuse_probability}");
+            }
+        }
+    }
+}
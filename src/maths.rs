@@ -50,6 +50,19 @@ impl Vec3 {
         Vec3([v.x, v.y, v.z])
     }
 
+    /// Two unit vectors that together with `self` (normalized) form an orthonormal basis
+    // https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+    pub fn orthonormal_basis(&self) -> (Vec3, Vec3) {
+        let n = self.normalize();
+        let sign = 1f64.copysign(n.z());
+        let a = -1. / (sign + n.z());
+        let b = n.x() * n.y() * a;
+        (
+            Vec3([1. + sign * n.x() * n.x() * a, sign * b, -sign * n.x()]),
+            Vec3([b, sign + n.y() * n.y() * a, -n.y()]),
+        )
+    }
+
     pub fn x(&self) -> f64 {
         self.0[0]
     }
@@ -108,3 +121,31 @@ impl Neg for Vec3 {
         Vec3(self.0.map(|x| -x))
     }
 }
+
+/// Orthonormal basis around a normal, for moving directions into a local shading frame where the normal is +z
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+    pub normal: Vec3,
+}
+
+impl Frame {
+    pub fn new(normal: Vec3) -> Self {
+        let normal = normal.normalize();
+        let (tangent, bitangent) = normal.orthonormal_basis();
+        Frame {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    pub fn to_local(self, v: Vec3) -> Vec3 {
+        Vec3([v.dotp(self.tangent), v.dotp(self.bitangent), v.dotp(self.normal)])
+    }
+
+    pub fn to_world(self, v: Vec3) -> Vec3 {
+        v.x() * self.tangent + v.y() * self.bitangent + v.z() * self.normal
+    }
+}
@@ -1,123 +1,427 @@
+use std::collections::HashMap;
+use std::io;
 use std::path::Path;
 
-use cgmath::{Matrix4, SquareMatrix, Vector4};
-use gltf::mesh::util::ReadIndices;
+use cgmath::{Matrix, Matrix4, SquareMatrix, Vector4, Zero};
+use gltf::json::extras::RawValue;
+use gltf::json::Value;
+use gltf::mesh::Mode;
 
-use crate::{intersections::Triangle, maths::Vec3, light_transport::{PBRMaterial, FColor}};
+use crate::{
+    animation::Animations,
+    deform::{joint_matrices, morph_weights, pose_primitive},
+    intersections::{Instance, TriGeometry, TriangleMesh},
+    light_transport::{
+        DirectionalLight, DiskLight, FColor, Light, PBRMaterial, PointLight, RectLight, SpotLight,
+        LUMENS_PER_WATT,
+    },
+    maths::Vec3,
+    motion::MotionTransform,
+};
 
-pub fn load_from_gltf<T: AsRef<str>>(path: T) -> Vec<Triangle> {
-    let path = Path::new(path.as_ref());
+/// A glTF document with its buffers, imported once for its triangles and lights, however often they are posed
+pub struct GltfFile {
+    pub document: gltf::Document,
+    pub buffers: Vec<gltf::buffer::Data>,
+}
 
-    let (document, buffers, _images) = gltf::import(path).unwrap();
+impl GltfFile {
+    /// Imports the file at `path`, along with the buffers it references
+    pub fn open<P: AsRef<Path>>(path: P) -> gltf::Result<Self> {
+        let (document, buffers, _images) = gltf::import(path)?;
+        Ok(GltfFile { document, buffers })
+    }
 
-    let mut tris = Vec::new();
+    /// Imports a .glb or self-contained .gltf from memory
+    pub fn from_slice(bytes: &[u8]) -> gltf::Result<Self> {
+        let (document, buffers, _images) = gltf::import_slice(bytes)?;
+        Ok(GltfFile { document, buffers })
+    }
+}
 
-    dbg!(document.meshes().len());
+/// Imports the triangles of the scene posed at `time` seconds into its animations, moving along
+/// with them for the `shutter` seconds after it
+pub fn load_from_gltf(file: &GltfFile, time: f32, shutter: f32) -> gltf::Result<TriGeometry> {
+    let GltfFile { document, buffers } = file;
+    let animations = Animations::load(document, buffers);
 
-    for (idx, mesh) in document.meshes().enumerate() {
-        let mut vertices = Vec::new();
-        let mut indices = Vec::new();
+    let mut meshes = Vec::new();
+    let mut materials = Vec::new();
+    // glTF material index to its ID in `materials`, primitives without one share the default material
+    let mut material_ids = HashMap::new();
+    let mut instances = Vec::new();
+    // glTF mesh index to the mesh built for it, nodes sharing a mesh become instances of one
+    let mut shared = HashMap::new();
 
-        dbg!(idx);
+    let opening = world_transforms(document, &animations, time);
+    let closing = world_transforms(document, &animations, time + shutter);
+    let (world_open, world_close) = (by_node_index(document, &opening), by_node_index(document, &closing));
+    for ((node, transf), (_, transf_close)) in opening.iter().zip(&closing) {
+        let Some(mesh) = node.mesh() else {
+            continue;
+        };
 
-        let current_node = document.nodes().nth(mesh.index());
-        let transform = current_node.clone().unwrap().transform();
+        // skinned vertices are placed by their joints, the transform of the node does not apply to them
+        let joints = node.skin().map(|skin| {
+            (joint_matrices(&skin, buffers, &world_open), joint_matrices(&skin, buffers, &world_close))
+        });
+        // rigid motion moves the instance, sheared motion does not interpolate and is moved vertex by vertex
+        let motion = MotionTransform::new(swap_y_z(*transf), swap_y_z(*transf_close));
+        let moves = transf_close != transf;
+        // baked meshes are posed in the world and placed where they are, they cannot be shared
+        let baked = joints.is_some() || (moves && motion.is_none());
+        let (vertex_transf, vertex_transf_close) = match baked && joints.is_none() {
+            true => (*transf, *transf_close),
+            false => (Matrix4::identity(), Matrix4::identity()),
+        };
+        let instance = |mesh| match baked {
+            true => Instance::new(mesh, Matrix4::identity(), None, None),
+            false => Instance::new(mesh, swap_y_z(*transf), motion.filter(|_| moves), None),
+        };
 
-        if Matrix4::from(current_node.unwrap().transform().matrix()).determinant() > 0. {
-            println!("CCW");
-        } else {
-            println!("CW");
+        // morph targets are blended per node, so only meshes without them are the same wherever they are placed
+        let shareable = !baked && mesh.primitives().all(|primitive| primitive.morph_targets().len() == 0);
+        if shareable {
+            if let Some(idx) = shared.get(&mesh.index()) {
+                instances.push(instance(*idx));
+                continue;
+            }
         }
-        dbg!(mesh.name());
-        dbg!(mesh.primitives().len());
+
+        let mut vertices = Vec::new();
+        let mut vertices_close = Vec::new();
+        let mut normals = Vec::new();
+        let mut indices = Vec::new();
+        let mut triangle_materials = Vec::new();
+        // primitives without normals get zeros, so the buffer stays one entry per vertex
+        let mut has_normals = false;
+        let normal_transf = vertex_transf.invert().unwrap_or(vertex_transf).transpose();
+
+        let weights = morph_weights(node, &animations, time);
+        let weights_close = morph_weights(node, &animations, time + shutter);
+        // whether the mesh changes shape while the shutter is open
+        let mut deforms = false;
 
         for primitive in mesh.primitives() {
-            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-            if let Some(it) = reader.read_positions() {
-                for [x, y, z] in it {
-                    let v = Vector4::from([x, y, z, 1.]);
+            // indices count from the first vertex of their primitive
+            let first_vertex = vertices.len();
+            let posed = pose_primitive(&primitive, buffers, &weights, joints.as_ref().map(|j| j.0.as_slice()));
+            let posed_close = (shutter > 0.).then(|| {
+                pose_primitive(
+                    &primitive,
+                    buffers,
+                    &weights_close,
+                    joints.as_ref().map(|j| j.1.as_slice()),
+                )
+            });
+            let posed_close = posed_close.as_ref().unwrap_or(&posed);
+            deforms |= posed_close.positions != posed.positions;
 
-                    let transf = Matrix4::from(transform.clone().matrix());
+            for (position, position_close) in posed.positions.iter().zip(&posed_close.positions) {
+                let v = position.extend(1.);
 
-                    let nv = transf * v;
-                    // NOTE: z and y swapped
-                    let nv = Vec3([nv.x, nv.z, nv.y].map(|a| a as f64));
+                let nv = vertex_transf * v;
+                // NOTE: z and y swapped
+                let nv = Vec3([nv.x, nv.z, nv.y].map(|a| a as f64));
 
-                    vertices.push((nv.x(), nv.y(), nv.z()));
+                vertices.push(nv);
 
-                    let output = format!("vtx {} {} {}\n", x, y, z);
-                    dbg!(&output);
-                }
+                let close = vertex_transf_close * position_close.extend(1.);
+                vertices_close.push(Vec3([close.x, close.z, close.y].map(|a| a as f64)));
             }
 
-            // every indices element represents one colour: associate color attributes here
+            has_normals |= !posed.normals.is_empty();
+            for i in 0..posed.positions.len() {
+                let n = posed
+                    .normals
+                    .get(i)
+                    .map_or(Vector4::zero(), |n| normal_transf * n.extend(0.));
+                normals.push(Vec3([n.x, n.z, n.y].map(|a| a as f64)));
+            }
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
 
-        let color = {
-            let color = primitive
-            .material()
-            .pbr_metallic_roughness()
-            .base_color_factor();
-        let metallic_factor = primitive
-            .material()
-            .pbr_metallic_roughness()
-            .metallic_factor() as f64;
-        let ior = primitive.material().ior().unwrap_or(1.0) as f64;
-        let transmissive = primitive
-            .material()
-            .transmission()
-            .and_then(|x| Some(x.transmission_factor()))
-            .unwrap_or(0.0) as f64;
-
-            PBRMaterial {
-            color: FColor { rgb: [color[0] as f64, color[1] as f64, color[2] as f64] }, // ignore alpha
-            metallic_factor,
-            ior,
-            transmissive,
-        }};
+            // every indices element represents one colour: associate color attributes here
 
-            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-            if let Some(it) = reader.read_indices() {
-                if let ReadIndices::U16(it) = it {
-                    let chunks = it.collect::<Vec<_>>();
-                    let chunks = chunks.chunks_exact(3);
-                    for c in chunks.clone() {
-                        let output = format!("idx {} {} {}\n", c[0], c[1], c[2]);
-                        dbg!(&output);
-                        indices.push((c[0], c[1], c[2], color));
+            let color = {
+                let color = primitive.material().pbr_metallic_roughness().base_color_factor();
+                let metallic_factor = primitive.material().pbr_metallic_roughness().metallic_factor() as f64;
+                let roughness = primitive.material().pbr_metallic_roughness().roughness_factor() as f64;
+                // exporters leave out KHR_materials_ior for the glTF default of 1.5
+                let ior = primitive.material().ior().unwrap_or(1.5) as f64;
+                let transmissive = primitive
+                    .material()
+                    .transmission()
+                    .map(|x| x.transmission_factor())
+                    .unwrap_or(0.0) as f64;
+                let volume = primitive.material().volume();
+                let thickness = volume.as_ref().map(|v| v.thickness_factor()).unwrap_or(0.0) as f64;
+                let attenuation_color = volume.as_ref().map(|v| v.attenuation_color()).unwrap_or([1.0; 3]);
+                let attenuation_distance = volume
+                    .as_ref()
+                    .map(|v| v.attenuation_distance() as f64)
+                    .unwrap_or(f64::INFINITY);
+                let emissive = {
+                    let factor = primitive.material().emissive_factor();
+                    let strength = primitive.material().emissive_strength().unwrap_or(1.0);
+                    FColor {
+                        rgb: factor.map(|x| (x * strength) as f64),
                     }
-                    assert!(chunks.remainder().is_empty());
+                };
+
+                PBRMaterial {
+                    color: FColor {
+                        rgb: [color[0] as f64, color[1] as f64, color[2] as f64],
+                    }, // ignore alpha
+                    metallic_factor,
+                    roughness,
+                    ior,
+                    transmissive,
+                    thickness,
+                    attenuation_color: FColor {
+                        rgb: attenuation_color.map(|x| x as f64),
+                    },
+                    attenuation_distance,
+                    emissive,
+                    id: primitive.material().index(),
                 }
-            } else {
-                dbg!("NO");
+            };
+
+            let material = *material_ids.entry(primitive.material().index()).or_insert_with(|| {
+                materials.push(color);
+                materials.len() as u32 - 1
+            });
+
+            // primitives without indices use their vertices in order
+            let primitive_indices = match reader.read_indices() {
+                Some(it) => it.into_u32().collect(),
+                None => (0..posed.positions.len() as u32).collect(),
+            };
+            for c in triangles(primitive.mode(), primitive_indices)? {
+                let first_vertex = first_vertex as u32;
+                // swapping z and y mirrors the mesh, swap two corners as well so the
+                // counterclockwise winding and with it the face normal points outside again
+                indices.push([c[0], c[2], c[1]].map(|i| first_vertex + i));
+                triangle_materials.push(material);
             }
 
             //dbg!(colour);
-            
+
             /*
             if let Some(texture) = primitive.material().pbr_metallic_roughness().base_color_texture(){
                 let image = &images[texture.texture().index()];
-                
+
                 let data: ImageBuffer<Rgb<u8>, _> = image::ImageBuffer::from_raw(image.width, image.height, image.pixels.clone()).unwrap();
-                
+
                 data.save("test.jpg").unwrap();
             }*/
         }
 
-        for i in indices {
-            let t = Triangle {
-                vertices: [
-                    vertices[i.0 as usize],
-                    vertices[i.1 as usize],
-                    vertices[i.2 as usize],
-                ]
-                .map(|v| (v.0, v.1, v.2))
-                .map(|v| Vec3([v.0 as f64, v.1 as f64, v.2 as f64])),
-                pbr_mat: i.3,
-            };
-            tris.push(t);
+        if !(deforms || (baked && moves)) {
+            vertices_close.clear();
+        }
+        if !has_normals {
+            normals.clear();
         }
+        meshes.push(TriangleMesh::new(vertices, vertices_close, normals, indices, triangle_materials));
+        if shareable {
+            shared.insert(mesh.index(), meshes.len() - 1);
+        }
+        instances.push(instance(meshes.len() - 1));
     }
 
     //dbg!(tris)
-    tris
+    Ok(TriGeometry::new(meshes, materials, instances))
+}
+
+/// The counterclockwise triangles the `indices` of a primitive describe in its `mode`,
+/// points and lines have none and are rejected
+fn triangles(mode: Mode, indices: Vec<u32>) -> gltf::Result<Vec<[u32; 3]>> {
+    let invalid = |reason: String| gltf::Error::Io(io::Error::new(io::ErrorKind::InvalidData, reason));
+    match mode {
+        Mode::Triangles => {
+            if !indices.len().is_multiple_of(3) {
+                return Err(invalid(format!("{} indices don't make whole triangles", indices.len())));
+            }
+            Ok(indices.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect())
+        }
+        // every other triangle of a strip is wound clockwise, swap its first two corners
+        Mode::TriangleStrip => Ok(indices
+            .windows(3)
+            .enumerate()
+            .map(|(i, c)| if i % 2 == 0 { [c[0], c[1], c[2]] } else { [c[1], c[0], c[2]] })
+            .collect()),
+        Mode::TriangleFan => Ok(indices.windows(2).skip(1).map(|c| [indices[0], c[0], c[1]]).collect()),
+        Mode::Points | Mode::Lines | Mode::LineLoop | Mode::LineStrip => {
+            Err(invalid(format!("{mode:?} primitives can't be rendered, only triangles")))
+        }
+    }
+}
+
+/// Imports the KHR_lights_punctual lights and the area lights of `area_light` placed in the scene,
+/// where they are at `time` seconds
+pub fn load_lights_from_gltf(file: &GltfFile, time: f32) -> Vec<Light> {
+    let animations = Animations::load(&file.document, &file.buffers);
+
+    world_transforms(&file.document, &animations, time)
+        .into_iter()
+        .filter_map(|(node, transf)| {
+            if let Some(light) = node.extras().as_ref().and_then(|extras| area_light(extras, transf)) {
+                return Some(light);
+            }
+            let light = node.light()?;
+
+            // lights sit at the node origin and shine along the local -z axis
+            let point = transf * Vector4::new(0., 0., 0., 1.);
+            let direction = transf * Vector4::new(0., 0., -1., 0.);
+            // NOTE: z and y swapped, same as the vertices
+            let point = Vec3([point.x, point.z, point.y].map(|a| a as f64));
+            let direction = Vec3([direction.x, direction.z, direction.y].map(|a| a as f64)).normalize();
+
+            let color = FColor { rgb: light.color().map(|x| x as f64) };
+            // candela and lux to W/sr and W/m^2
+            let intensity = light.intensity() as f64 / LUMENS_PER_WATT;
+            let range = light.range().map(|x| x as f64);
+
+            Some(match light.kind() {
+                gltf::khr_lights_punctual::Kind::Point => Light::Point(PointLight {
+                    point,
+                    color,
+                    intensity,
+                    range,
+                }),
+                gltf::khr_lights_punctual::Kind::Spot {
+                    inner_cone_angle,
+                    outer_cone_angle,
+                } => Light::Spot(SpotLight {
+                    point,
+                    direction,
+                    color,
+                    intensity,
+                    range,
+                    inner_cone_angle: inner_cone_angle as f64,
+                    outer_cone_angle: outer_cone_angle as f64,
+                }),
+                gltf::khr_lights_punctual::Kind::Directional => {
+                    Light::Directional(DirectionalLight {
+                        direction,
+                        color,
+                        intensity,
+                    })
+                }
+            })
+        })
+        .collect()
+}
+
+/// Rectangle or disk light set in the extras of a node, e.g. as custom properties in Blender:
+/// `{"area_light": "rect", "width": 2, "height": 1, "color": [1, 1, 1], "intensity": 5}`
+/// or `{"area_light": "disk", "radius": 0.5, ..}`. It lies in the xy plane of the node, centered
+/// at its origin, and shines along its -z axis like the punctual lights. `intensity` is the emitted
+/// radiance in W/(sr m^2).
+fn area_light(extras: &RawValue, transf: Matrix4<f32>) -> Option<Light> {
+    let extras: Value = gltf::json::deserialize::from_str(extras.get()).ok()?;
+    let number = |key: &str, default: f64| extras[key].as_f64().unwrap_or(default);
+    let color = match extras["color"].as_array() {
+        Some(rgb) if rgb.len() == 3 => FColor { rgb: [0, 1, 2].map(|i| rgb[i].as_f64().unwrap_or(1.)) },
+        _ => FColor::from([1., 1., 1.]),
+    };
+    let intensity = number("intensity", 1.);
+    // NOTE: z and y swapped, same as the vertices
+    let world = |v: Vector4<f32>| {
+        let v = transf * v;
+        Vec3([v.x, v.z, v.y].map(|a| a as f64))
+    };
+    let center = world(Vector4::unit_w());
+    let forward = world(-Vector4::unit_z()).normalize();
+
+    match extras["area_light"].as_str()? {
+        "rect" => {
+            let edge_u = world(number("width", 1.) as f32 * Vector4::unit_x());
+            let edge_v = world(number("height", 1.) as f32 * Vector4::unit_y());
+            // swapping z and y mirrors the edges, keep them in the order that emits forward
+            let (edge_u, edge_v) = if edge_u.cross(edge_v).dotp(forward) >= 0. {
+                (edge_u, edge_v)
+            } else {
+                (edge_v, edge_u)
+            };
+            Some(Light::Rect(RectLight {
+                corner: center - 0.5 * (edge_u + edge_v),
+                edge_u,
+                edge_v,
+                color,
+                intensity,
+            }))
+        }
+        "disk" => Some(Light::Disk(DiskLight {
+            center,
+            normal: forward,
+            // scaled like the node
+            radius: number("radius", 0.5) * world(Vector4::unit_x()).length(),
+            color,
+            intensity,
+        })),
+        _ => None,
+    }
+}
+
+/// `transf` in world space, where y and z are swapped
+fn swap_y_z(transf: Matrix4<f32>) -> Matrix4<f64> {
+    let swap = Matrix4::from_cols(Vector4::unit_x(), Vector4::unit_z(), Vector4::unit_y(), Vector4::unit_w());
+    swap * transf.cast::<f64>().expect("f32 fits f64") * swap
+}
+
+/// World transform of every node by index, for looking up joints
+fn by_node_index(document: &gltf::Document, transforms: &[(gltf::Node, Matrix4<f32>)]) -> Vec<Matrix4<f32>> {
+    let mut world = vec![Matrix4::identity(); document.nodes().len()];
+    for (node, transf) in transforms {
+        world[node.index()] = *transf;
+    }
+    world
+}
+
+/// Walks the node hierarchy of the default scene and returns every node with its world transform,
+/// posed at `time` seconds into the animations
+fn world_transforms<'a>(
+    document: &'a gltf::Document,
+    animations: &Animations,
+    time: f32,
+) -> Vec<(gltf::Node<'a>, Matrix4<f32>)> {
+    fn walk<'a>(
+        node: gltf::Node<'a>,
+        parent: Matrix4<f32>,
+        animations: &Animations,
+        time: f32,
+        out: &mut Vec<(gltf::Node<'a>, Matrix4<f32>)>,
+    ) {
+        let transf = parent * animations.local_transform(&node, time);
+        for child in node.children() {
+            walk(child, transf, animations, time, out);
+        }
+        out.push((node, transf));
+    }
+
+    let mut out = Vec::new();
+    if let Some(scene) = document.default_scene().or_else(|| document.scenes().next()) {
+        for node in scene.nodes() {
+            walk(node, Matrix4::identity(), animations, time, &mut out);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_and_fans_become_counterclockwise_triangles() {
+        let strip = triangles(Mode::TriangleStrip, vec![0, 1, 2, 3, 4]).unwrap();
+        assert_eq!(strip, vec![[0, 1, 2], [2, 1, 3], [2, 3, 4]]);
+
+        let fan = triangles(Mode::TriangleFan, vec![0, 1, 2, 3]).unwrap();
+        assert_eq!(fan, vec![[0, 1, 2], [0, 2, 3]]);
+
+        assert!(triangles(Mode::Triangles, vec![0, 1, 2, 3]).is_err());
+        assert!(triangles(Mode::Lines, vec![0, 1]).is_err());
+    }
 }
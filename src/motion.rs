@@ -0,0 +1,92 @@
+use cgmath::{InnerSpace, Matrix3, Matrix4, Quaternion, SquareMatrix, Vector3, VectorSpace};
+
+use crate::maths::Vec3;
+
+/// A transform split into translation, rotation and scale, which interpolate without distorting the object
+#[derive(Debug, Clone, Copy)]
+struct Decomposed {
+    translation: Vector3<f64>,
+    rotation: Quaternion<f64>,
+    scale: Vector3<f64>,
+}
+
+impl Decomposed {
+    /// `None` for sheared matrices, which do not split up this way
+    fn new(matrix: Matrix4<f64>) -> Option<Self> {
+        let translation = matrix.w.truncate();
+        let mut columns = [matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate()];
+        let mut scale = Vector3::new(columns[0].magnitude(), columns[1].magnitude(), columns[2].magnitude());
+        if scale.x == 0. || scale.y == 0. || scale.z == 0. {
+            return None;
+        }
+        for (column, s) in columns.iter_mut().zip([scale.x, scale.y, scale.z]) {
+            *column /= s;
+        }
+        // a mirroring transform is a rotation with a negative scale
+        let mut rotation = Matrix3::from_cols(columns[0], columns[1], columns[2]);
+        if rotation.determinant() < 0. {
+            scale.x = -scale.x;
+            rotation.x = -rotation.x;
+        }
+
+        let decomposed = Decomposed {
+            translation,
+            rotation: Quaternion::from(rotation).normalize(),
+            scale,
+        };
+        let difference = decomposed.matrix() - matrix;
+        let error = (0..4)
+            .flat_map(|col| (0..4).map(move |row| difference[col][row].abs()))
+            .fold(0., f64::max);
+        let size = scale.x.abs().max(scale.y.abs()).max(scale.z.abs());
+        (error <= 1e-4 * size).then_some(decomposed)
+    }
+
+    fn matrix(&self) -> Matrix4<f64> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    fn lerp(&self, other: &Decomposed, s: f64) -> Decomposed {
+        Decomposed {
+            translation: self.translation.lerp(other.translation, s),
+            rotation: self.rotation.slerp(other.rotation, s),
+            scale: self.scale.lerp(other.scale, s),
+        }
+    }
+}
+
+/// How a rigid object moves while the shutter is open, from its world transform when the shutter opens
+/// to the one when it closes. Rotations are interpolated along their arc, so spinning objects blur in circles.
+#[derive(Debug, Clone, Copy)]
+pub struct MotionTransform {
+    open: Decomposed,
+    close: Decomposed,
+}
+
+impl MotionTransform {
+    /// `None` when either transform is sheared, the object's vertices have to be moved one by one then
+    pub fn new(open: Matrix4<f64>, close: Matrix4<f64>) -> Option<Self> {
+        Some(MotionTransform {
+            open: Decomposed::new(open)?,
+            close: Decomposed::new(close)?,
+        })
+    }
+
+    /// The object's transform at `time`, 0 opening and 1 closing the shutter
+    pub fn at(&self, time: f64) -> Matrix4<f64> {
+        self.open.lerp(&self.close, time).matrix()
+    }
+}
+
+pub fn transform_point(matrix: &Matrix4<f64>, point: Vec3) -> Vec3 {
+    let p = matrix * Vector3::from(point.0).extend(1.);
+    Vec3([p.x, p.y, p.z])
+}
+
+/// Directions only rotate and scale, they do not move along
+pub fn transform_vector(matrix: &Matrix4<f64>, vector: Vec3) -> Vec3 {
+    let v = matrix * Vector3::from(vector.0).extend(0.);
+    Vec3([v.x, v.y, v.z])
+}
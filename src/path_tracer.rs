@@ -0,0 +1,235 @@
+use crate::integrator::{Integrator, LightPaths};
+use crate::intersections::{Intersect, IntersectionResult};
+use crate::light_transport::{Bsdf, FColor, Light};
+use crate::maths::{Frame, Vec3};
+use crate::ray::Ray;
+use crate::sampling::Sampler;
+use crate::scene::Scene;
+
+/// Hard limit on the path length, Russian roulette usually ends paths long before
+const MAX_BOUNCES: usize = 32;
+/// Bounces every path survives before Russian roulette may terminate it
+const ROULETTE_DEPTH: usize = 3;
+
+/// Unbiased global illumination by unidirectional path tracing.
+/// Every vertex samples the lights (next-event estimation) and the BSDF, both are combined by
+/// multiple importance sampling with the power heuristic (Veach, 1997, chapter 9).
+pub struct PathTracer;
+
+impl Integrator for PathTracer {
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> FColor {
+        trace_path(ray, scene, sampler).total()
+    }
+
+    fn light_paths(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Option<LightPaths> {
+        Some(trace_path(ray, scene, sampler))
+    }
+}
+
+/// Radiance arriving along `ray`
+fn trace_path(ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> LightPaths {
+    let mut paths = LightPaths::BLACK;
+    // path throughput, leaving out the BSDF of the first surface
+    let mut throughput = FColor::from([1., 1., 1.]);
+    // what the first surface passes on through its diffuse and its specular part, sorts all later light
+    let mut first_bounce: Option<(FColor, FColor)> = None;
+    let mut ray = ray.clone();
+    // solid angle pdf of the BSDF sample that spawned `ray`; None for camera rays and specular bounces,
+    // which light sampling cannot reach
+    let mut bsdf_pdf: Option<f64> = None;
+
+    for bounce in 0..MAX_BOUNCES {
+        let IntersectionResult::Hit {
+            idx,
+            point,
+            normal,
+            shading_normal,
+            t,
+            material,
+            ..
+        } = scene.geometry.intersect(&ray)
+        else {
+            let background = throughput * escaped(scene, ray.dir, bsdf_pdf);
+            match first_bounce {
+                None => paths.emission += background,
+                Some(weights) => add_indirect(&mut paths, weights, background),
+            }
+            break;
+        };
+
+        let dir = ray.dir.normalize();
+        let distance = t * ray.dir.length();
+
+        throughput = throughput * material.transmittance_to(ray.dir, normal, distance);
+
+        let mut emitted = FColor::BLACK;
+        if material.is_emissive() {
+            let weight = match bsdf_pdf {
+                Some(pdf) => {
+                    // area pdf to solid angle: dist^2 / cos at the emitter
+                    let cos_light = normal.dotp(dir).abs();
+                    let light_pdf = scene.emitter_area_pdf(idx, ray.time) * distance * distance / cos_light;
+                    power_heuristic(pdf, light_pdf)
+                }
+                None => 1.,
+            };
+            emitted = throughput * material.emissive * weight;
+        }
+
+        let frame = material.shading_frame(normal, shading_normal, -dir);
+        let wo = frame.to_local(-dir);
+        let bsdf = material.bsdf();
+
+        let (direct_diffuse, direct_specular) = sample_lights(scene, sampler, point, normal, frame, wo, bsdf.as_ref(), ray.time);
+        match first_bounce {
+            None => {
+                paths.emission += emitted;
+                paths.direct_diffuse += throughput * direct_diffuse;
+                paths.direct_specular += throughput * direct_specular;
+            }
+            Some(weights) => add_indirect(&mut paths, weights, emitted + throughput * (direct_diffuse + direct_specular)),
+        }
+
+        let Some(sample) = bsdf.sample(wo, sampler.next_2d()) else {
+            break;
+        };
+        if sample.pdf <= 0. || sample.f.is_black() {
+            break;
+        }
+        let weight = sample.f * (sample.wi.z().abs() / sample.pdf);
+        match first_bounce {
+            None => {
+                let diffuse = if sample.delta {
+                    FColor::BLACK
+                } else {
+                    bsdf.eval_diffuse(wo, sample.wi) * (sample.wi.z().abs() / sample.pdf)
+                };
+                first_bounce = Some((diffuse, weight - diffuse));
+            }
+            Some(_) => throughput = throughput * weight,
+        }
+        bsdf_pdf = if sample.delta { None } else { Some(sample.pdf) };
+        ray = Ray::spawn(point, normal, frame.to_world(sample.wi), f64::INFINITY, ray.time);
+
+        // end dim paths randomly, survivors carry the energy of the terminated ones
+        if bounce >= ROULETTE_DEPTH {
+            let full_throughput = first_bounce.map_or(throughput, |(diffuse, specular)| throughput * (diffuse + specular));
+            let survival = full_throughput.rgb.iter().fold(0., |max: f64, c| max.max(*c)).min(0.95);
+            if sampler.next_f64() >= survival {
+                break;
+            }
+            throughput = throughput * (1. / survival);
+        }
+    }
+
+    paths
+}
+
+/// Sorts light that scattered after the first surface by the part of its BSDF that sent the path on
+fn add_indirect(paths: &mut LightPaths, (diffuse, specular): (FColor, FColor), radiance: FColor) {
+    paths.indirect_diffuse += diffuse * radiance;
+    paths.indirect_specular += specular * radiance;
+}
+
+/// Direct light at a path vertex, weighted against reaching the same light by BSDF sampling.
+/// Returns what the diffuse and the specular part of the BSDF reflect. Shadows are cast at shutter `time`.
+#[allow(clippy::too_many_arguments)]
+fn sample_lights(
+    scene: &Scene,
+    sampler: &mut Sampler,
+    point: Vec3,
+    normal: Vec3,
+    frame: Frame,
+    wo: Vec3,
+    bsdf: &dyn Bsdf,
+    time: f64,
+) -> (FColor, FColor) {
+    let mut diffuse = FColor::BLACK;
+    let mut specular = FColor::BLACK;
+    let mut add = |incoming: FColor, wi: Vec3| {
+        let f_diffuse = bsdf.eval_diffuse(wo, wi);
+        diffuse += incoming * f_diffuse;
+        specular += incoming * (bsdf.eval(wo, wi) - f_diffuse);
+    };
+
+    // emissive surfaces
+    if let Some((emitter, area_pdf)) = scene.sample_emitter(sampler.next_f64(), time) {
+        let (light_point, light_normal) = emitter.sample(sampler.next_2d());
+        let distance = (light_point - point).length();
+        let light_dir = (light_point - point).normalize();
+        let cos_light = light_normal.dotp(light_dir).abs();
+
+        let wi = frame.to_local(light_dir);
+        let f = bsdf.eval(wo, wi);
+        if distance > 0. && cos_light > 0. && !f.is_black() {
+            let shadow_ray = Ray::spawn(point, normal, light_dir, distance * (1. - 1e-4), time);
+            if !scene.geometry.occluded(&shadow_ray) {
+                let light_pdf = area_pdf * distance * distance / cos_light;
+                let weight = power_heuristic(light_pdf, bsdf.pdf(wo, wi));
+                add(emitter.pbr_mat.emissive * (wi.z().abs() * weight / light_pdf), wi);
+            }
+        }
+    }
+
+    for light in &scene.lights {
+        let light_sample = light.illuminate(point, sampler.next_2d());
+        if light_sample.radiance.is_black() {
+            continue;
+        }
+
+        let wi = frame.to_local(light_sample.dir);
+        let f = bsdf.eval(wo, wi);
+        if f.is_black() {
+            continue;
+        }
+
+        let shadow_ray = Ray::spawn(point, normal, light_sample.dir, light_sample.distance * (1. - 1e-4), time);
+        if scene.geometry.occluded(&shadow_ray) {
+            continue;
+        }
+
+        let incoming = if light.is_infinite() {
+            let light_pdf = light.pdf(light_sample.dir);
+            if light_pdf <= 0. {
+                continue;
+            }
+            let weight = power_heuristic(light_pdf, bsdf.pdf(wo, wi));
+            light.emitted(light_sample.dir) * (weight / light_pdf)
+        } else {
+            // punctual and analytic area lights are not part of the geometry, BSDF samples never hit them
+            light_sample.radiance
+        };
+        add(incoming * wi.z().abs(), wi);
+    }
+
+    (diffuse, specular)
+}
+
+/// Radiance a path picks up when it leaves the scene
+fn escaped(scene: &Scene, dir: Vec3, bsdf_pdf: Option<f64>) -> FColor {
+    if !scene.lights.iter().any(Light::is_infinite) {
+        return scene.background(dir);
+    }
+
+    scene
+        .lights
+        .iter()
+        .filter(|light| light.is_infinite())
+        .fold(FColor::BLACK, |sum, light| {
+            let weight = match bsdf_pdf {
+                Some(pdf) => power_heuristic(pdf, light.pdf(dir)),
+                None => 1.,
+            };
+            sum + light.emitted(dir) * weight
+        })
+}
+
+/// MIS weight of a sample drawn with pdf `f` against another strategy with pdf `g`
+fn power_heuristic(f: f64, g: f64) -> f64 {
+    let (f2, g2) = (f * f, g * g);
+    if f2 + g2 > 0. {
+        f2 / (f2 + g2)
+    } else {
+        0.
+    }
+}
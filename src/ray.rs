@@ -1,5 +1,3 @@
-use rayon::prelude::*;
-
 use crate::maths::{Vec2, Vec3};
 
 #[derive(Debug, Clone)]
@@ -8,12 +6,30 @@ pub struct Ray {
     pub dir: Vec3, // direction
     pub min: f64,  // Start of intersection testing
     pub max: f64,  // End of intersection testing
+    /// when during the shutter interval the ray travels, 0 at opening and 1 at closing
+    pub time: f64,
 }
 
 impl Ray {
     pub fn at(&self, t: f64) -> Vec3 {
         self.pos + t * self.dir
     }
+
+    /// Ray leaving a hit point at shutter `time`, offset to the side of the surface it travels to
+    pub fn spawn(hit_point: Vec3, hit_normal: Vec3, dir: Vec3, max: f64, time: f64) -> Ray {
+        let vf = if dir.dotp(hit_normal) < 0. {
+            -1.
+        } else {
+            1.
+        };
+        Ray {
+            dir,
+            pos: hit_point + vf * hit_normal * 1e-4,
+            min: 0.,
+            max,
+            time,
+        }
+    }
 }
 
 pub enum CameraFovDirection {
@@ -62,22 +78,24 @@ const MSAA_16: [[f64; 2]; 16] = [
 
 const MSAA_SAMPLES: [&[[f64; 2]]; 5] = [&MSAA_1, &MSAA_2, &MSAA_4, &MSAA_8, &MSAA_16];
 
+/// The MSAA pattern with `samples` positions, there are patterns for 2^0=1 .. 2^4=16 samples
+pub fn msaa_offsets(samples: usize) -> Option<&'static [[f64; 2]]> {
+    let idx = match samples {
+        1 => 0,
+        2 => 1,
+        4 => 2,
+        8 => 3,
+        16 => 4,
+        _ => return None,
+    };
+    Some(MSAA_SAMPLES[idx])
+}
+
 pub trait RayGenerator {
     fn gen_ray(&self, pixel: Vec2) -> Ray;
     fn gen_samples(&self, pixel: Vec2, samples: usize) -> Vec<Ray> {
-        let samples = {
-            let idx = match samples {
-                1 => 0,
-                2 => 1,
-                4 => 2,
-                8 => 3,
-                16 => 4,
-                _ => panic!("Not a valid sampling size. 2^0=1 .. 2^4=16 is supported."),
-            };
-            MSAA_SAMPLES[idx]
-        };
-
-        samples
+        msaa_offsets(samples)
+            .expect("Not a valid sampling size. 2^0=1 .. 2^4=16 is supported.")
             .iter()
             .map(|offset| {
                 let new_pixel = pixel + Vec2(*offset);
@@ -129,6 +147,7 @@ impl RayGenerator for PinholePerspective {
             dir,
             min: 0.,
             max: f64::MAX,
+            time: 0.,
         }
     }
 }
@@ -136,7 +155,7 @@ impl RayGenerator for PinholePerspective {
 pub struct ThinLensPerspective {}
 
 impl RayGenerator for ThinLensPerspective {
-    fn gen_ray(&self, pixel: Vec2) -> Ray {
+    fn gen_ray(&self, _pixel: Vec2) -> Ray {
         todo!()
     }
 }
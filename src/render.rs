@@ -0,0 +1,335 @@
+use std::f64::consts::FRAC_PI_4;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use image::{ImageError, ImageResult};
+use indicatif::ProgressBar;
+
+use crate::aov::Aov;
+use crate::camera::Camera;
+use crate::checkpoint::{self, Checkpoints};
+use crate::denoise::{self, Guides};
+use crate::environment::EnvironmentLight;
+use crate::light_transport::{FColor, Light, SphereLight};
+use crate::maths::{Vec2, Vec3};
+use crate::model::{self, GltfFile};
+use crate::ray::{CameraFovDirection, PinholePerspective};
+use crate::renderer::{Convergence, RenderProgress, Renderer};
+use crate::scene::Scene;
+use crate::settings::Settings;
+use crate::sky::SkyLight;
+
+pub const SAMPLES: usize = 2;
+/// Extra samples a noisy pixel gets at a time with adaptive sampling, before its error is checked again
+pub const ADAPTIVE_BATCH: usize = 4;
+
+const IMAGE_SIZE: (usize, usize) = (2560/2, 1440/2);
+/// brings the kcd/m^2 luminances of the sky to the range of the other lights
+const SKY_EXPOSURE: f64 = 0.03;
+
+/// Renders the glTF scene `model` on this machine and saves the images next to `output`.
+/// `on_row` is called after every rendered row and stops the render when it returns false,
+/// nothing is saved then. Returns whether the render got to the end.
+pub fn render(
+    settings: &Settings,
+    model: &str,
+    output: &str,
+    on_row: &mut dyn FnMut(&RenderProgress, &Renderer) -> bool,
+) -> ImageResult<bool> {
+    render_gltf(settings, &open_model(model)?, model, output, on_row)
+}
+
+/// `render` with the glTF file `model` already imported as `gltf`
+fn render_gltf(
+    settings: &Settings,
+    gltf: &GltfFile,
+    model: &str,
+    output: &str,
+    on_row: &mut dyn FnMut(&RenderProgress, &Renderer) -> bool,
+) -> ImageResult<bool> {
+    let environment = read_environment(settings)?;
+    let scene = load_scene(gltf, environment.as_deref(), settings)?;
+    let (mut renderer, scene_hash) = new_renderer(settings, &scene, &fs::read(model)?, environment.as_deref());
+    let height = renderer.framebuffer.height;
+
+    let mut progress = RenderProgress {
+        pass: 1,
+        spp: settings.progressive.unwrap_or(SAMPLES),
+        row: 0,
+    };
+    let mut checkpoints = settings
+        .checkpoint
+        .as_ref()
+        .map(|path| Checkpoints::new(path.into(), Duration::from_secs_f64(settings.checkpoint_interval)));
+    if let (true, Some(checkpoints)) = (settings.resume, &checkpoints) {
+        progress = checkpoint::load(&checkpoints.path, scene_hash, &mut renderer)?;
+        println!("Resuming pass {} at row {}", progress.pass, progress.row);
+    }
+
+    println!("Ray-tracing..");
+
+    let preview = settings.preview.as_deref().unwrap_or(output);
+    let start = Instant::now();
+    loop {
+        let bar = ProgressBar::new(height as u64);
+        bar.set_position(progress.row as u64);
+        while progress.row < height {
+            renderer.render_row(progress.row, progress.spp);
+            progress.row += 1;
+            bar.inc(1);
+
+            if let Some(checkpoints) = checkpoints.as_mut().filter(|c| c.due()) {
+                checkpoints.save(scene_hash, &progress, &renderer)?;
+            }
+            if !on_row(&progress, &renderer) {
+                bar.abandon();
+                return Ok(false);
+            }
+        }
+        bar.finish();
+
+        if settings.progressive.is_some() {
+            println!("Pass {}: {:.1} spp after {:.1?}", progress.pass, renderer.mean_spp(), start.elapsed());
+            save_image(&renderer, settings, preview)?;
+        }
+        let out_of_time = settings.time_limit.is_some_and(|limit| start.elapsed().as_secs_f64() >= limit);
+        if renderer.finished() || out_of_time {
+            break;
+        }
+        progress = RenderProgress {
+            pass: progress.pass + 1,
+            // without progressive passes, only noisy pixels are sampled again
+            spp: settings.progressive.unwrap_or(ADAPTIVE_BATCH),
+            row: 0,
+        };
+    }
+
+    println!("Finished :)");
+    save_outputs(settings, &renderer, output)?;
+    Ok(true)
+}
+
+/// Renders the frames of `settings.frames` one after the other, numbering the outputs like `frame_path`
+pub fn render_frames(
+    settings: &Settings,
+    model: &str,
+    output: &str,
+    on_row: &mut dyn FnMut(&RenderProgress, &Renderer) -> bool,
+) -> ImageResult<bool> {
+    let (first, last) = settings.frames.unwrap_or((0, 0));
+    // imported once, every frame poses it anew
+    let gltf = open_model(model)?;
+    for frame in first..=last {
+        println!("Frame {frame} of {first}..{last}");
+        let settings = Settings {
+            time: frame as f64 / settings.fps,
+            ..settings.clone()
+        };
+        if !render_gltf(&settings, &gltf, model, &frame_path(output, frame), on_row)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// `images/out.png` becomes `images/out.0007.png` for frame 7
+pub fn frame_path(output: &str, frame: usize) -> String {
+    let path = Path::new(output);
+    let extension = path.extension().map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()));
+    path.with_extension(format!("{frame:04}{extension}")).to_string_lossy().into_owned()
+}
+
+/// Imports the glTF file `model`
+pub fn open_model(model: &str) -> ImageResult<GltfFile> {
+    GltfFile::open(model).map_err(gltf_error)
+}
+
+/// Imports the .glb or self-contained .gltf file `bytes`
+pub fn model_from_slice(bytes: &[u8]) -> ImageResult<GltfFile> {
+    GltfFile::from_slice(bytes).map_err(gltf_error)
+}
+
+fn gltf_error(err: gltf::Error) -> ImageError {
+    ImageError::IoError(io::Error::other(err))
+}
+
+/// The `--environment` file of `settings`, if any
+pub fn read_environment(settings: &Settings) -> io::Result<Option<Vec<u8>>> {
+    settings.environment.as_ref().map(fs::read).transpose()
+}
+
+/// The triangles and lights of the `gltf` scene at `settings.time` seconds into its animations,
+/// with the `environment` map read from the `--environment` file and the lights configured here.
+/// The triangles move on during the shutter.
+pub fn load_scene(gltf: &GltfFile, environment: Option<&[u8]>, settings: &Settings) -> ImageResult<Scene> {
+    let (time, shutter) = (settings.time as f32, settings.shutter as f32);
+    let geom = model::load_from_gltf(gltf, time, shutter).map_err(gltf_error)?;
+
+    let mut lights = model::load_lights_from_gltf(gltf, time);
+
+    if let Some(environment) = environment {
+        let rotation = settings.env_rotation.to_radians();
+        let environment = EnvironmentLight::from_slice(environment, rotation, settings.env_intensity)?;
+        lights.push(Light::Environment(Box::new(environment)));
+    }
+
+    if settings.sky {
+        lights.push(Light::Sky(Box::new(SkyLight::new(
+            settings.sun_dir,
+            settings.turbidity,
+            settings.ground_albedo,
+            SKY_EXPOSURE,
+        ))));
+    }
+
+    if lights.is_empty() {
+        // fall back to a light next to the camera when the scene has none
+        lights = vec![
+            /*PointLight { // pink
+                point: Vec3([-2., 0., 2.]),
+                color: Color::from([214, 2, 112]).to_fcolor(),
+                intensity: 30.,
+                range: None,
+            },
+            PointLight { // blue
+                point: Vec3([1., 0., 1.]),
+                color: Color::from([0, 56, 168]).to_fcolor(),
+                intensity: 30.,
+                range: None,
+            },*/
+            /*Light::Point(PointLight { //white
+                point: Vec3([0., 0., 0.5]),
+                color: FColor::from([1., 1., 1.]),
+                intensity: 30.0,
+                range: None,
+            }),
+            Light::Rect(RectLight { // softbox above
+                corner: Vec3([-1., 2., -2.]),
+                edge_u: Vec3([2., 0., 0.]),
+                edge_v: Vec3([0., 0., -2.]),
+                color: FColor::from([1., 1., 1.]),
+                intensity: 5.,
+            }),*/
+            Light::Sphere(SphereLight { //white
+                center: Vec3([0., 0., 0.5]),
+                radius: 0.2,
+                color: FColor::from([1., 1., 1.]),
+                intensity: 30. / (std::f64::consts::PI * 0.2 * 0.2), // as bright as the 30 W/sr point light
+            }),
+        ];
+    }
+
+    Ok(Scene::new(geom, lights))
+}
+
+/// The renderer for `settings`, and the hash of everything its samples depend on.
+/// `model` and `environment` hold the files the scene was loaded from.
+pub fn new_renderer<'a>(
+    settings: &Settings,
+    scene: &'a Scene,
+    model: &[u8],
+    environment: Option<&[u8]>,
+) -> (Renderer<'a>, u64) {
+    let dim @ (width, height) = IMAGE_SIZE;
+
+    let cam = Camera {
+        position: Vec3([0., 0., 0.]),
+        direction: Vec3([0., 0., -1.]),
+        up: Vec3([0., 1., 0.]),
+        fov: 0.0,
+    };
+
+    let perspective = PinholePerspective {
+        camera_fov: FRAC_PI_4,
+        fov_dir: CameraFovDirection::Horizontal,
+        image_size: Vec2([dim.0 as f64, dim.1 as f64]),
+    };
+
+    // the denoiser is guided by the first hits, render them even when they are not saved
+    let mut aovs = settings.aovs.clone();
+    if settings.denoise > 0. {
+        for guide in [Aov::Albedo, Aov::Normal, Aov::Depth] {
+            if !aovs.contains(&guide) {
+                aovs.push(guide);
+            }
+        }
+    }
+
+    let sample_more = settings.adaptive_threshold.is_some() || settings.progressive.is_some();
+    let convergence = Convergence {
+        threshold: settings.adaptive_threshold,
+        max_spp: if sample_more { settings.max_spp } else { SAMPLES },
+    };
+    let mut scene_files = vec![model];
+    scene_files.extend(environment);
+    let environment = (settings.env_rotation, settings.env_intensity);
+    let sky = settings.sky.then_some((settings.sun_dir, settings.turbidity, settings.ground_albedo, SKY_EXPOSURE));
+    let scene_hash = checkpoint::scene_hash(
+        &scene_files,
+        scene,
+        (dim, &settings.integrator, settings.shadow_samples, settings.ao_distance, settings.ao_samples, &aovs, convergence, settings.progressive, (environment, sky), (settings.time, settings.shutter, settings.camera_velocity)),
+    );
+
+    let camera_close = (settings.shutter > 0. && settings.camera_velocity != Vec3([0.; 3])).then(|| Camera {
+        position: cam.position + settings.shutter * settings.camera_velocity,
+        ..cam.clone()
+    });
+    let integrator = settings.build_integrator();
+    let mut renderer = Renderer::new(scene, cam, perspective, integrator, convergence, aovs, width, height);
+    renderer.camera_close = camera_close;
+    (renderer, scene_hash)
+}
+
+/// Saves the image to `output`, and the requested AOVs and the heatmap next to it
+pub fn save_outputs(settings: &Settings, renderer: &Renderer, output: &str) -> ImageResult<()> {
+    println!("Saving...");
+    save_image(renderer, settings, output)?;
+
+    if !settings.aovs.is_empty() {
+        if settings.aov_exr {
+            renderer
+                .aov_buffers
+                .save_layered(&settings.aovs, Path::new(output).with_extension("aovs.exr"))?;
+        } else {
+            renderer.aov_buffers.save_separate(&settings.aovs, output)?;
+        }
+    }
+
+    if settings.spp_heatmap {
+        renderer
+            .framebuffer
+            .sample_heatmap(renderer.convergence.max_spp)
+            .save(Path::new(output).with_extension("spp.png"))?;
+    }
+
+    Ok(())
+}
+
+/// Tone maps the rendered image to `path`, denoised if the settings ask for it, and writes the linear
+/// radiance next to it with `--exr`. The renderer keeps the noisy image, later passes add to it.
+fn save_image(renderer: &Renderer, settings: &Settings, path: &str) -> ImageResult<()> {
+    let aov_buffers = &renderer.aov_buffers;
+    let denoised;
+    let framebuffer = if let (true, Some(albedo), Some(normal), Some(depth)) = (
+        settings.denoise > 0.,
+        aov_buffers.get(Aov::Albedo),
+        aov_buffers.get(Aov::Normal),
+        aov_buffers.get(Aov::Depth),
+    ) {
+        println!("Denoising...");
+        let mut framebuffer = renderer.framebuffer.clone();
+        denoise::denoise(&mut framebuffer, &Guides { albedo, normal, depth }, settings.denoise);
+        denoised = framebuffer;
+        &denoised
+    } else {
+        &renderer.framebuffer
+    };
+
+    framebuffer.to_image().save(path)?;
+    if settings.exr {
+        framebuffer.to_hdr_image().save(Path::new(path).with_extension("exr"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use std::io::{self, Read, Write};
+
+use crate::aov::{self, Aov, AovBuffers};
+use crate::camera::Camera;
+use crate::checkpoint::{read_u64, write_u64};
+use crate::framebuffer::Framebuffer;
+use crate::integrator::Integrator;
+use crate::light_transport::FColor;
+use crate::maths::Vec2;
+use crate::ray::{msaa_offsets, PinholePerspective, Ray, RayGenerator};
+use crate::sampling::Sampler;
+use crate::scene::Scene;
+
+/// The variance of fewer samples is too unreliable to stop at, two black samples look noise free
+const ADAPTIVE_MIN_SAMPLES: usize = 8;
+
+/// When a pixel has enough samples
+#[derive(Debug, Clone, Copy)]
+pub struct Convergence {
+    /// relative error adaptive sampling aims for, `None` samples every pixel up to `max_spp`
+    pub threshold: Option<f64>,
+    pub max_spp: usize,
+}
+
+/// Where a render is, rows of a pass are rendered top to bottom
+#[derive(Debug, Clone, Copy)]
+pub struct RenderProgress {
+    /// counting from 1
+    pub pass: usize,
+    /// samples per pixel the pass takes
+    pub spp: usize,
+    /// next row to render
+    pub row: usize,
+}
+
+/// A rectangle of pixels, from `x0, y0` up to `x1, y1` excluded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    /// Cuts a `width` by `height` image into tiles of `size` by `size` pixels, smaller ones at the edges
+    pub fn split(width: usize, height: usize, size: usize) -> Vec<Tile> {
+        (0..height)
+            .step_by(size)
+            .flat_map(|y0| {
+                (0..width).step_by(size).map(move |x0| Tile {
+                    x0,
+                    y0,
+                    x1: (x0 + size).min(width),
+                    y1: (y0 + size).min(height),
+                })
+            })
+            .collect()
+    }
+
+    /// Indices of the tile's pixels in an image `width` pixels wide, row by row
+    pub fn pixels(self, width: usize) -> impl Iterator<Item = usize> {
+        (self.y0..self.y1).flat_map(move |y| (self.x0..self.x1).map(move |x| y * width + x))
+    }
+}
+
+/// Renders the scene in passes, each adding samples to the pixels that still need them.
+/// Every pixel keeps its own sampler across passes, so the image only depends on the passes taken.
+pub struct Renderer<'a> {
+    pub scene: &'a Scene,
+    /// camera when the shutter opens
+    pub camera: Camera,
+    /// camera when the shutter closes, `None` when it stands still
+    pub camera_close: Option<Camera>,
+    pub perspective: PinholePerspective,
+    pub integrator: Box<dyn Integrator>,
+    pub convergence: Convergence,
+    pub framebuffer: Framebuffer,
+    pub aov_buffers: AovBuffers,
+    /// one per pixel, row by row
+    samplers: Vec<Sampler>,
+    /// whether any triangle moves while the shutter is open
+    scene_moves: bool,
+}
+
+impl<'a> Renderer<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        scene: &'a Scene,
+        camera: Camera,
+        perspective: PinholePerspective,
+        integrator: Box<dyn Integrator>,
+        convergence: Convergence,
+        aovs: Vec<Aov>,
+        width: usize,
+        height: usize,
+    ) -> Self {
+        Renderer {
+            scene,
+            camera,
+            camera_close: None,
+            perspective,
+            integrator,
+            convergence,
+            framebuffer: Framebuffer::new(width, height),
+            aov_buffers: AovBuffers::new(aovs, width, height),
+            samplers: (0..width * height).map(|p| Sampler::new(p as u64)).collect(),
+            scene_moves: scene.geometry.has_motion(),
+        }
+    }
+
+    /// Takes up to `spp` more samples in every pixel of row `y` that has not converged yet
+    pub fn render_row(&mut self, y: usize, spp: usize) {
+        for x in 0..self.framebuffer.width {
+            if self.converged(x, y) {
+                continue;
+            }
+            let taken = self.framebuffer.counts[y * self.framebuffer.width + x] as usize;
+            self.sample_pixel(x, y, spp.min(self.convergence.max_spp - taken));
+        }
+    }
+
+    /// Samples the pixels of `tile` until they converge, `spp` at first and then `batch` at a time.
+    /// Every pixel gets the same samples as when rendering the whole image in passes.
+    pub fn render_tile(&mut self, tile: Tile, spp: usize, batch: usize) {
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let mut spp = spp;
+                while !self.converged(x, y) {
+                    let taken = self.framebuffer.counts[y * self.framebuffer.width + x] as usize;
+                    self.sample_pixel(x, y, spp.min(self.convergence.max_spp - taken));
+                    spp = batch;
+                }
+            }
+        }
+    }
+
+    /// Writes the image and AOVs of `tile`, to send the rendered tile elsewhere
+    pub fn write_tile<W: Write>(&self, w: &mut W, tile: Tile) -> io::Result<()> {
+        let width = self.framebuffer.width;
+        self.framebuffer.write_pixels(w, tile.pixels(width))?;
+        self.aov_buffers.write_pixels(w, tile.pixels(width))
+    }
+
+    /// Reads back what `write_tile` wrote into the same tile of this image
+    pub fn read_tile<R: Read>(&mut self, r: &mut R, tile: Tile) -> io::Result<()> {
+        let width = self.framebuffer.width;
+        self.framebuffer.read_pixels(r, tile.pixels(width))?;
+        self.aov_buffers.read_pixels(r, tile.pixels(width))
+    }
+
+    /// Whether every pixel has converged
+    pub fn finished(&self) -> bool {
+        let (width, height) = (self.framebuffer.width, self.framebuffer.height);
+        (0..height).all(|y| (0..width).all(|x| self.converged(x, y)))
+    }
+
+    /// Samples per pixel, averaged over the image
+    pub fn mean_spp(&self) -> f64 {
+        let counts = &self.framebuffer.counts;
+        counts.iter().map(|c| *c as f64).sum::<f64>() / counts.len().max(1) as f64
+    }
+
+    /// Writes the image, AOVs and samplers for a checkpoint
+    pub fn write_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.framebuffer.write_state(w)?;
+        self.aov_buffers.write_state(w)?;
+        self.samplers
+            .iter()
+            .flat_map(Sampler::to_raw)
+            .try_for_each(|x| write_u64(w, x))
+    }
+
+    /// Reads back what `write_state` wrote, rendering on gives the same image as if it never stopped
+    pub fn read_state<R: Read>(&mut self, r: &mut R) -> io::Result<()> {
+        self.framebuffer.read_state(r)?;
+        self.aov_buffers.read_state(r)?;
+        for sampler in &mut self.samplers {
+            *sampler = Sampler::from_raw([read_u64(r)?, read_u64(r)?]);
+        }
+        Ok(())
+    }
+
+    fn converged(&self, x: usize, y: usize) -> bool {
+        let taken = self.framebuffer.counts[y * self.framebuffer.width + x] as usize;
+        if taken >= self.convergence.max_spp {
+            return true;
+        }
+        match self.convergence.threshold {
+            Some(threshold) => taken >= ADAPTIVE_MIN_SAMPLES && self.framebuffer.relative_error(x, y) <= threshold,
+            None => false,
+        }
+    }
+
+    /// The first samples of a pixel follow the MSAA pattern when there is one for `spp`,
+    /// later ones are spread randomly over the pixel. The AOVs average every sample like the image does.
+    /// With anything moving, every sample is taken at a random time while the shutter is open.
+    fn sample_pixel(&mut self, x: usize, y: usize, spp: usize) {
+        let p = y * self.framebuffer.width + x;
+        let taken = self.framebuffer.counts[p] as usize;
+        let first = taken == 0;
+        let pixel = Vec2([x as f64, y as f64]);
+        let sampler = &mut self.samplers[p];
+
+        let rays: Vec<Ray> = if first && msaa_offsets(spp).is_some() {
+            self.perspective.gen_samples(pixel, spp)
+        } else {
+            (0..spp)
+                .map(|_| self.perspective.gen_ray(pixel + Vec2(sampler.next_2d())))
+                .collect()
+        };
+        // still scenes draw no times, so their samples stay the same as without a shutter
+        let moving = self.camera_close.is_some() || self.scene_moves;
+        let rays: Vec<Ray> = rays
+            .into_iter()
+            .map(|mut ray| {
+                if moving {
+                    ray.time = sampler.next_f64();
+                }
+                match &self.camera_close {
+                    Some(close) => self.camera.lerp(close, ray.time).ray_cam_to_world(&ray),
+                    None => self.camera.ray_cam_to_world(&ray),
+                }
+            })
+            .collect();
+
+        let aovs = self.aov_buffers.aovs.as_slice();
+        let mut aov_samples = Vec::new();
+        for ray in rays {
+            let color = trace(&ray, self.scene, sampler, self.integrator.as_ref(), aovs, &mut aov_samples);
+            self.framebuffer.add_sample(x, y, color);
+        }
+        self.aov_buffers.add_samples(x, y, taken, &aov_samples);
+    }
+}
+
+/// Color of a camera ray, pushing its AOVs to `aov_samples` if any are requested
+fn trace(
+    ray: &Ray,
+    scene: &Scene,
+    sampler: &mut Sampler,
+    integrator: &dyn Integrator,
+    aovs: &[Aov],
+    aov_samples: &mut Vec<Vec<FColor>>,
+) -> FColor {
+    if aovs.is_empty() {
+        return integrator.li(ray, scene, sampler);
+    }
+    let paths = integrator.light_paths(ray, scene, sampler);
+    let color = match paths {
+        Some(paths) => paths.total(),
+        None => integrator.li(ray, scene, sampler),
+    };
+    aov_samples.push(aov::evaluate(aovs, ray, scene, paths));
+    color
+}
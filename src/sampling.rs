@@ -0,0 +1,235 @@
+use crate::maths::Vec3;
+
+/// Small PCG32 random number generator.
+/// Every pixel gets its own seeded instance, so renders are reproducible.
+// https://www.pcg-random.org/download.html
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    state: u64,
+    inc: u64,
+}
+
+impl Sampler {
+    pub fn new(seed: u64) -> Self {
+        let mut sampler = Sampler {
+            state: 0,
+            inc: (seed << 1) | 1,
+        };
+        sampler.next_u32();
+        sampler.state = sampler.state.wrapping_add(0x853c_49e6_748f_ea9b);
+        sampler.next_u32();
+        sampler
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// The generator's state, for checkpoints
+    pub fn to_raw(&self) -> [u64; 2] {
+        [self.state, self.inc]
+    }
+
+    /// Continues where `to_raw` left off
+    pub fn from_raw([state, inc]: [u64; 2]) -> Self {
+        Sampler { state, inc }
+    }
+
+    /// Uniform sample in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        self.next_u32() as f64 / (u32::MAX as f64 + 1.)
+    }
+
+    pub fn next_2d(&mut self) -> [f64; 2] {
+        [self.next_f64(), self.next_f64()]
+    }
+}
+
+/// Uniformly distributed barycentric coordinates on a triangle (pbrt, 3rd ed., 13.6.5)
+pub fn uniform_triangle(u: [f64; 2]) -> [f64; 3] {
+    let su = u[0].sqrt();
+    let b0 = 1. - su;
+    let b1 = u[1] * su;
+    [b0, b1, 1. - b0 - b1]
+}
+
+/// Uniformly distributed point on the unit disk, concentric mapping (pbrt, 3rd ed., 13.6.2)
+pub fn concentric_disk(u: [f64; 2]) -> [f64; 2] {
+    let offset = [2. * u[0] - 1., 2. * u[1] - 1.];
+    if offset == [0., 0.] {
+        return [0., 0.];
+    }
+    let (r, theta) = if offset[0].abs() > offset[1].abs() {
+        (offset[0], std::f64::consts::FRAC_PI_4 * (offset[1] / offset[0]))
+    } else {
+        (
+            offset[1],
+            std::f64::consts::FRAC_PI_2 - std::f64::consts::FRAC_PI_4 * (offset[0] / offset[1]),
+        )
+    };
+    [r * theta.cos(), r * theta.sin()]
+}
+
+/// Direction on the hemisphere around +z, distributed by cos(theta) (Malley's method)
+pub fn cosine_hemisphere(u: [f64; 2]) -> Vec3 {
+    let [x, y] = concentric_disk(u);
+    let z = f64::max(0., 1. - x * x - y * y).sqrt();
+    Vec3([x, y, z])
+}
+
+/// Uniformly distributed direction inside a cone around +z, as (cos theta, phi)
+pub fn uniform_cone(u: [f64; 2], cos_theta_max: f64) -> (f64, f64) {
+    let cos_theta = (1. - u[0]) + u[0] * cos_theta_max;
+    (cos_theta, 2. * std::f64::consts::PI * u[1])
+}
+
+/// Uniformly distributed direction on the unit sphere
+pub fn uniform_sphere(u: [f64; 2]) -> Vec3 {
+    let z = 1. - 2. * u[0];
+    let r = f64::max(0., 1. - z * z).sqrt();
+    let phi = 2. * std::f64::consts::PI * u[1];
+    Vec3([r * phi.cos(), r * phi.sin(), z])
+}
+
+/// Piecewise-constant 1D distribution over [0, 1), sampled by inverting its CDF (pbrt, 3rd ed., 13.3.1)
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1].abs() / n as f64;
+        }
+
+        let integral = cdf[n];
+        if integral == 0. {
+            // nothing to importance sample: fall back to uniform
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        } else {
+            cdf.iter_mut().for_each(|c| *c /= integral);
+        }
+
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    /// Returns the sample in [0, 1), its pdf and the index of the segment it fell into
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        let n = self.func.len();
+        let offset = (self.cdf.partition_point(|c| *c <= u).max(1) - 1).min(n - 1);
+
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0. {
+            du /= width;
+        }
+
+        ((offset as f64 + du) / n as f64, self.pdf(offset), offset)
+    }
+
+    pub fn pdf(&self, offset: usize) -> f64 {
+        if self.integral > 0. {
+            self.func[offset].abs() / self.integral
+        } else {
+            1.
+        }
+    }
+}
+
+/// Piecewise-constant 2D distribution over [0, 1)^2, rows are picked first (pbrt, 3rd ed., 13.6.7)
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `func` is stored row by row, `width` values per row
+    pub fn new(func: &[f64], width: usize) -> Self {
+        let conditional: Vec<Distribution1D> = func
+            .chunks_exact(width)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|c| c.integral()).collect());
+
+        Distribution2D {
+            conditional,
+            marginal,
+        }
+    }
+
+    /// Returns the sample as (column, row) coordinates in [0, 1) and its pdf
+    pub fn sample(&self, u: [f64; 2]) -> ([f64; 2], f64) {
+        let (v, pdf_v, row) = self.marginal.sample(u[1]);
+        let (u, pdf_u, _) = self.conditional[row].sample(u[0]);
+        ([u, v], pdf_u * pdf_v)
+    }
+
+    /// Density `sample` returns the point `uv` with
+    pub fn pdf(&self, uv: [f64; 2]) -> f64 {
+        let rows = self.conditional.len();
+        let row = ((uv[1] * rows as f64) as usize).min(rows - 1);
+        let columns = self.conditional[row].func.len();
+        let column = ((uv[0] * columns as f64) as usize).min(columns - 1);
+        self.conditional[row].pdf(column) * self.marginal.pdf(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bright in one corner, dark in the middle rows, one black row
+    fn distribution() -> Distribution2D {
+        let (width, height) = (7, 5);
+        let func: Vec<f64> = (0..width * height)
+            .map(|i| if i / width == 3 { 0. } else { 0.1 + ((i * 13) % 17) as f64 })
+            .collect();
+        Distribution2D::new(&func, width)
+    }
+
+    #[test]
+    fn distribution_2d_pdf_integrates_to_one() {
+        // the pdf is constant over each cell, summing it at the cell centers integrates it exactly
+        for (distribution, width, height) in [(distribution(), 7, 5), (Distribution2D::new(&[0.; 12], 4), 4, 3)] {
+            let integral: f64 = (0..width * height)
+                .map(|i| distribution.pdf([((i % width) as f64 + 0.5) / width as f64, ((i / width) as f64 + 0.5) / height as f64]))
+                .sum::<f64>()
+                / (width * height) as f64;
+            assert!((integral - 1.).abs() < 1e-9, "integral {integral}");
+        }
+    }
+
+    #[test]
+    fn distribution_2d_samples_with_their_pdf() {
+        let distribution = distribution();
+        let mut sampler = Sampler::new(3);
+        for _ in 0..1000 {
+            let (uv, pdf) = distribution.sample(sampler.next_2d());
+            assert!(uv.iter().all(|x| (0. ..1.).contains(x)), "{uv:?}");
+            assert!(pdf > 0., "sampled {uv:?} where the function is 0");
+            assert!((pdf - distribution.pdf(uv)).abs() < 1e-9 * pdf, "{pdf} at {uv:?}");
+        }
+    }
+}
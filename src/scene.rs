@@ -0,0 +1,97 @@
+use crate::intersections::{TriGeometry, Triangle};
+use crate::light_transport::{FColor, Light};
+use crate::maths::Vec3;
+
+/// What rays escaping the scene see when there is no environment
+const BACKGROUND: FColor = FColor { rgb: [1. / 256., 1. / 256., 1. / 256.] };
+
+pub struct Scene {
+    pub geometry: TriGeometry,
+    pub lights: Vec<Light>,
+    /// Indices of the emissive triangles in `geometry`, ascending
+    emitters: Vec<usize>,
+    /// Running sum of the emitter areas when the shutter opens, emitters are picked proportional to them
+    emitter_cdf: Vec<f64>,
+}
+
+impl Scene {
+    pub fn new(geometry: TriGeometry, lights: Vec<Light>) -> Self {
+        // placed where they are when the shutter opens, instances can scale their mesh.
+        // Emitters that grow or shrink during the shutter keep being picked by this area.
+        let (emitters, areas): (Vec<usize>, Vec<f64>) = (0..geometry.triangle_count())
+            .map(|idx| (idx, geometry.posed(idx, 0.)))
+            .filter(|(_, tri)| tri.pbr_mat.is_emissive() && tri.area() > 0.)
+            .map(|(idx, tri)| (idx, tri.area()))
+            .unzip();
+
+        let emitter_cdf = areas
+            .iter()
+            .scan(0., |sum, area| {
+                *sum += area;
+                Some(*sum)
+            })
+            .collect();
+
+        Scene {
+            geometry,
+            lights,
+            emitters,
+            emitter_cdf,
+        }
+    }
+
+    /// Picks an emissive triangle for `u` in [0, 1), where it is at shutter `time`.
+    /// Returns the triangle and the area pdf of a point sampled uniformly on it.
+    pub fn sample_emitter(&self, u: f64, time: f64) -> Option<(Triangle, f64)> {
+        let total_area = *self.emitter_cdf.last()?;
+        let target = u * total_area;
+        let pick = self
+            .emitter_cdf
+            .partition_point(|sum| *sum <= target)
+            .min(self.emitters.len() - 1);
+
+        let emitter = self.geometry.posed(self.emitters[pick], time);
+        let pdf = self.area_pdf(pick, &emitter);
+        Some((emitter, pdf))
+    }
+
+    /// Area pdf `sample_emitter` picks a point on the emissive triangle `idx` with at shutter `time`,
+    /// 0 for triangles that are not emitters
+    pub fn emitter_area_pdf(&self, idx: usize, time: f64) -> f64 {
+        match self.emitters.binary_search(&idx) {
+            Ok(pick) => self.area_pdf(pick, &self.geometry.posed(idx, time)),
+            Err(_) => 0.,
+        }
+    }
+
+    /// Probability of picking emitter `pick` by its area when the shutter opens, spread over `posed`,
+    /// where it is when sampled. The two areas only differ for emitters scaling or deforming during the shutter.
+    fn area_pdf(&self, pick: usize, posed: &Triangle) -> f64 {
+        let total_area = self.emitter_cdf[self.emitter_cdf.len() - 1];
+        let area = self.emitter_cdf[pick] - if pick > 0 { self.emitter_cdf[pick - 1] } else { 0. };
+        let posed_area = posed.area();
+        if posed_area > 0. {
+            area / (total_area * posed_area)
+        } else {
+            0.
+        }
+    }
+
+    /// Radiance seen by a ray leaving the scene in direction `dir`
+    pub fn background(&self, dir: Vec3) -> FColor {
+        let mut has_environment = false;
+        let mut color = FColor::BLACK;
+        for light in &self.lights {
+            if light.is_infinite() {
+                has_environment = true;
+                color += light.emitted(dir);
+            }
+        }
+
+        if has_environment {
+            color
+        } else {
+            BACKGROUND
+        }
+    }
+}
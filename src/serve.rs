@@ -0,0 +1,351 @@
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use rei::model::GltfFile;
+use rei::render;
+use rei::settings::Settings;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest scene accepted in the body of a post, the whole body is held in memory
+const MAX_UPLOAD: u64 = 256 << 20;
+
+/// Where a job is
+#[derive(Debug, Clone, PartialEq)]
+enum JobState {
+    Queued,
+    Rendering,
+    Done,
+    Cancelled,
+    Failed(String),
+}
+
+/// A render posted to the API
+struct Job {
+    settings: Settings,
+    /// glTF file rendered, uploaded or from the models directory
+    model: String,
+    /// whether `model` was uploaded, it is deleted once the job is over
+    uploaded: bool,
+    /// holds the uploaded scene and the images
+    dir: PathBuf,
+    state: JobState,
+    /// pass being rendered and the rows of it that are done
+    pass: usize,
+    row: usize,
+    rows: usize,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Job {
+    fn to_json(&self, id: usize) -> Value {
+        let (state, error) = match &self.state {
+            JobState::Queued => ("queued", None),
+            JobState::Rendering => ("rendering", None),
+            JobState::Done => ("done", None),
+            JobState::Cancelled => ("cancelled", None),
+            JobState::Failed(err) => ("failed", Some(err)),
+        };
+        json!({
+            "id": id,
+            "state": state,
+            "error": error,
+            "pass": self.pass,
+            "row": self.row,
+            "rows": self.rows,
+            "files": self.files(),
+        })
+    }
+
+    /// Names of the images rendered so far
+    fn files(&self) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut files: Vec<String> = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.starts_with("image."))
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Deletes the uploaded scene, the images stay until the service stops
+    fn remove_upload(&self) {
+        if self.uploaded {
+            let _ = fs::remove_file(&self.model);
+        }
+    }
+}
+
+type Jobs = Arc<Mutex<Vec<Job>>>;
+
+/// Serves the render API at `address`, rendering one job at a time in the order they were posted.
+/// Posts without a scene render `default_model`. Clients only get to read files from the directory
+/// of `default_model`, the models directory.
+///
+/// - `POST /jobs?<option>=<value>&<flag>`: queues a render with the command line options, e.g.
+///   `?integrator=path&aov=depth,normal`. The body is a .glb or self-contained .gltf to render,
+///   `?model=<path>` renders a file of the models directory instead. `?environment=<path>` is
+///   looked up there as well.
+/// - `GET /jobs`, `GET /jobs/<id>`: state and progress of the jobs
+/// - `GET /jobs/<id>/<file>`: the images of a finished job, `image.png`, `image.exr` and the AOVs,
+///   numbered like `image.0007.png` for the frames of an animation
+/// - `DELETE /jobs/<id>`: cancels a job, queued or rendering
+pub fn run(address: &str, default_model: &str) -> io::Result<()> {
+    let server = Server::http(address).map_err(io::Error::other)?;
+    let jobs_dir = std::env::temp_dir().join(format!("rei-serve-{}", std::process::id()));
+    fs::create_dir_all(&jobs_dir)?;
+    println!("Serving on http://{} with the jobs in {}", server.server_addr(), jobs_dir.display());
+
+    let jobs: Jobs = Arc::default();
+    let (queue, queued) = mpsc::channel();
+    let render_jobs = jobs.clone();
+    thread::spawn(move || render_queued(&render_jobs, queued));
+
+    for mut request in server.incoming_requests() {
+        let response = handle(&mut request, &jobs, &jobs_dir, default_model, &queue);
+        // the client may be gone, that is its problem
+        let _ = request.respond(response);
+    }
+    Ok(())
+}
+
+fn handle(
+    request: &mut Request,
+    jobs: &Jobs,
+    jobs_dir: &Path,
+    default_model: &str,
+    queue: &mpsc::Sender<usize>,
+) -> Response<Cursor<Vec<u8>>> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match (request.method(), segments.as_slice()) {
+        (Method::Post, ["jobs"]) => {
+            let too_large = || error(413, &format!("scenes are limited to {} MiB", MAX_UPLOAD >> 20));
+            if request.body_length().is_some_and(|length| length as u64 > MAX_UPLOAD) {
+                return too_large();
+            }
+            // one byte more than allowed tells a body without a length apart from one that fits
+            let mut body = Vec::new();
+            if let Err(err) = Read::take(request.as_reader(), MAX_UPLOAD + 1).read_to_end(&mut body) {
+                return error(400, &err.to_string());
+            }
+            if body.len() as u64 > MAX_UPLOAD {
+                return too_large();
+            }
+            match post_job(jobs, jobs_dir, default_model, query, &body) {
+                Ok(id) => {
+                    let _ = queue.send(id);
+                    let job = jobs.lock().unwrap()[id].to_json(id);
+                    json_response(201, &job)
+                }
+                Err(err) => error(400, &err),
+            }
+        }
+        (Method::Get, ["jobs"]) => {
+            let jobs = jobs.lock().unwrap();
+            let list: Vec<Value> = jobs.iter().enumerate().map(|(id, job)| job.to_json(id)).collect();
+            json_response(200, &Value::from(list))
+        }
+        (Method::Get, ["jobs", id]) => with_job(jobs, id, |id, job| json_response(200, &job.to_json(id))),
+        (Method::Get, ["jobs", id, file]) => with_job(jobs, id, |_, job| {
+            // only hand out the images, and only once they are complete
+            if job.state != JobState::Done || !job.files().iter().any(|name| name == file) {
+                return error(404, "no such image");
+            }
+            match fs::read(job.dir.join(file)) {
+                Ok(bytes) => Response::from_data(bytes).with_header(content_type(file)),
+                Err(err) => error(500, &err.to_string()),
+            }
+        }),
+        (Method::Delete, ["jobs", id]) => with_job(jobs, id, |id, job| {
+            job.cancel.store(true, Ordering::Relaxed);
+            if job.state == JobState::Queued {
+                job.state = JobState::Cancelled;
+                job.remove_upload();
+            }
+            json_response(200, &job.to_json(id))
+        }),
+        _ => error(404, "unknown route"),
+    }
+}
+
+/// Stores the job's scene and adds it to `jobs`, returns its id
+fn post_job(jobs: &Jobs, jobs_dir: &Path, default_model: &str, query: &str, body: &[u8]) -> Result<usize, String> {
+    let models_dir = Path::new(default_model).parent().unwrap_or(Path::new(""));
+    let mut model = default_model.to_string();
+    let mut args = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').map_or((pair, None), |(k, v)| (k, Some(v)));
+        let (key, value) = (percent_decode(key)?, value.map(percent_decode).transpose()?);
+        match (key.as_str(), value) {
+            ("model", Some(path)) => model = in_models_dir(models_dir, &path)?,
+            ("environment", Some(path)) => {
+                args.push("--environment".to_string());
+                args.push(in_models_dir(models_dir, &path)?);
+            }
+            (key, value) => {
+                args.push(format!("--{key}"));
+                args.extend(value);
+            }
+        }
+    }
+
+    let mut settings = Settings::from_args(args)?;
+    if settings.checkpoint.is_some() || settings.preview.is_some() {
+        return Err("jobs can't write checkpoints or previews".to_string());
+    }
+    // the API hands out the radiance and the AOVs as one file each
+    settings.exr = true;
+    settings.aov_exr = true;
+
+    let uploaded = !body.is_empty();
+    if uploaded {
+        // imported from memory, external buffers are rejected so uploads can't point at files of this machine
+        GltfFile::from_slice(body).map_err(|err| format!("invalid scene: {err}"))?;
+    }
+
+    let mut jobs = jobs.lock().unwrap();
+    let id = jobs.len();
+    let dir = jobs_dir.join(id.to_string());
+    fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+    if uploaded {
+        let scene = dir.join(if body.starts_with(b"glTF") { "scene.glb" } else { "scene.gltf" });
+        fs::write(&scene, body).map_err(|err| err.to_string())?;
+        model = scene.to_string_lossy().into_owned();
+    }
+
+    jobs.push(Job {
+        settings,
+        model,
+        uploaded,
+        dir,
+        state: JobState::Queued,
+        pass: 0,
+        row: 0,
+        rows: 0,
+        cancel: Arc::default(),
+    });
+    Ok(id)
+}
+
+/// `path` inside `models_dir`, clients can't reach out of it with `..` or absolute paths
+fn in_models_dir(models_dir: &Path, path: &str) -> Result<String, String> {
+    let relative = Path::new(path);
+    if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(format!("{path} is not a path inside the models directory"));
+    }
+    Ok(models_dir.join(relative).to_string_lossy().into_owned())
+}
+
+/// Renders the jobs whose ids arrive, skipping cancelled ones
+fn render_queued(jobs: &Jobs, queued: Receiver<usize>) {
+    for id in queued {
+        let (settings, model, output, cancel) = {
+            let mut jobs = jobs.lock().unwrap();
+            let job = &mut jobs[id];
+            if job.state != JobState::Queued {
+                continue;
+            }
+            job.state = JobState::Rendering;
+            let output = job.dir.join("image.png").to_string_lossy().into_owned();
+            (job.settings.clone(), job.model.clone(), output, job.cancel.clone())
+        };
+        println!("Rendering job {id}");
+
+        let render = if settings.frames.is_some() { render::render_frames } else { render::render };
+        // a scene that trips up the renderer fails its job, the queue goes on
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            render(&settings, &model, &output, &mut |progress, renderer| {
+                let mut jobs = jobs.lock().unwrap();
+                let job = &mut jobs[id];
+                (job.pass, job.row, job.rows) = (progress.pass, progress.row, renderer.framebuffer.height);
+                !cancel.load(Ordering::Relaxed)
+            })
+        }));
+
+        let mut jobs = jobs.lock().unwrap();
+        let job = &mut jobs[id];
+        job.state = match result {
+            Ok(Ok(true)) => JobState::Done,
+            Ok(Ok(false)) => JobState::Cancelled,
+            Ok(Err(err)) => JobState::Failed(err.to_string()),
+            Err(payload) => JobState::Failed(panic_message(payload.as_ref())),
+        };
+        job.remove_upload();
+    }
+}
+
+/// What a caught panic was about
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str));
+    format!("the renderer panicked: {}", message.unwrap_or("unknown cause"))
+}
+
+/// Runs `f` on the job with the id in the url
+fn with_job<F>(jobs: &Jobs, id: &str, f: F) -> Response<Cursor<Vec<u8>>>
+where
+    F: FnOnce(usize, &mut Job) -> Response<Cursor<Vec<u8>>>,
+{
+    let mut jobs = jobs.lock().unwrap();
+    match id.parse::<usize>().ok().filter(|id| *id < jobs.len()) {
+        Some(id) => f(id, &mut jobs[id]),
+        None => error(404, "no such job"),
+    }
+}
+
+fn json_response(status: u16, value: &Value) -> Response<Cursor<Vec<u8>>> {
+    Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error(status: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+    json_response(status, &json!({ "error": message }))
+}
+
+fn content_type(file: &str) -> Header {
+    let mime = match file.rsplit('.').next() {
+        Some("png") => "image/png",
+        Some("exr") => "image/x-exr",
+        _ => "application/octet-stream",
+    };
+    header("Content-Type", mime)
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+/// Decodes the `%2C` escapes of a query string, `+` stands for a space
+fn percent_decode(s: &str) -> Result<String, String> {
+    let mut bytes = Vec::new();
+    let mut chars = s.bytes();
+    while let Some(byte) = chars.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [chars.next(), chars.next()];
+                let hex = hex.map(|c| c.and_then(|c| (c as char).to_digit(16)));
+                match hex {
+                    [Some(high), Some(low)] => (high * 16 + low) as u8,
+                    _ => return Err(format!("invalid escape in {s}")),
+                }
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).map_err(|_| format!("{s} is not UTF-8"))
+}
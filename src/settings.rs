@@ -0,0 +1,336 @@
+use std::str::FromStr;
+
+use crate::aov::Aov;
+use crate::integrator::{AmbientOcclusion, DebugView, Integrator};
+use crate::light_transport::FColor;
+use crate::maths::Vec3;
+use crate::path_tracer::PathTracer;
+use crate::whitted::Whitted;
+
+pub const USAGE: &str = "\
+usage: rei [options]
+       rei coordinator <address> [options]
+       rei worker <address>
+       rei serve <address>
+
+commands:
+  coordinator          split the image into tiles for the workers connecting to address, e.g. 0.0.0.0:7878
+  worker               render tiles for the coordinator at address, with its scene and options
+  serve                render the scenes posted to the HTTP API at address one after the other, e.g. 127.0.0.1:8080
+
+options:
+  --integrator <name>  whitted (default), path, ao, normals, depth, albedo, barycentrics, triangles
+  --shadow-samples <n> shadow rays per area light and shading point in whitted mode, more give smoother
+                       penumbrae (default 8)
+  --ao-distance <d>    how far occluders darken a point in ao mode (default 1)
+  --ao-samples <n>     occlusion rays per camera ray in ao mode (default 16)
+  --aov <names>        comma separated passes to write next to the image, or all:
+                       depth, position, normal, albedo, triangle, material, direct_diffuse,
+                       direct_specular, indirect_diffuse, indirect_specular, emission
+  --aov-exr            write the passes as layers of one EXR instead of one EXR each
+  --exr                write the image in linear radiance as EXR next to the PNG
+  --denoise <strength> filter the noise guided by albedo, normal and depth, 1 is a good start (default 0, off)
+  --adaptive <error>   keep sampling pixels whose relative error is above this, e.g. 0.05 (default off)
+  --max-spp <n>        samples per pixel adaptive sampling and progressive rendering stop at (default 64)
+  --spp-heatmap        write the samples taken per pixel as a heatmap next to the image
+  --progressive <n>    render in passes of n samples per pixel, saving the image after each
+  --time-limit <s>     stop after the pass running when s seconds are over
+  --preview <path>     where progressive passes save the image, instead of overwriting the output
+  --checkpoint <path>  save the render state to path regularly, so it can be resumed
+  --checkpoint-interval <s>
+                       seconds between checkpoints (default 60)
+  --resume             continue the render saved in the --checkpoint file
+  --time <s>           pose the scene s seconds into its glTF animations (default 0)
+  --frames <a>..<b>    render the animation frames a to b, numbering the images, e.g. 0..47
+  --fps <n>            frames per second of --frames (default 24)
+  --shutter <s>        keep the shutter open for s seconds after each frame's time, blurring what moves
+                       (default 0, off), e.g. 0.02 for half of a 24 fps frame
+  --camera-velocity <x,y,z>
+                       units the camera moves per second while the shutter is open (default 0,0,0)
+  --environment <path> equirectangular .hdr or .exr lighting the scene from all around, e.g. a studio HDRI
+  --env-rotation <deg> turns the environment counterclockwise around the up axis (default 0)
+  --env-intensity <x>  scales the radiance of the environment (default 1)
+  --sky                light the scene with a daylight sky and sun, for outdoor scenes without an HDRI
+  --sun-dir <x,y,z>    direction towards the sun, +y is up (default 0.5,0.6,-0.6)
+  --turbidity <t>      haze of the sky, 2 (clear) to 10 (hazy) (default 3)
+  --ground-albedo <a>  reflectance of the ground below the sky, gray or r,g,b (default 0.2)";
+
+/// Render options picked on the command line
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub command: Command,
+    pub integrator: IntegratorKind,
+    /// shadow rays per area light of the Whitted integrator
+    pub shadow_samples: usize,
+    /// maximum occluder distance for ambient occlusion
+    pub ao_distance: f64,
+    pub ao_samples: usize,
+    /// passes rendered next to the color
+    pub aovs: Vec<Aov>,
+    /// one multi-layer EXR instead of a file per pass
+    pub aov_exr: bool,
+    /// the image as EXR next to the PNG
+    pub exr: bool,
+    /// denoiser strength, 0 turns it off
+    pub denoise: f64,
+    /// relative error adaptive sampling aims for, `None` takes the same samples everywhere
+    pub adaptive_threshold: Option<f64>,
+    pub max_spp: usize,
+    pub spp_heatmap: bool,
+    /// samples per pixel of each progressive pass, `None` renders the image in one go
+    pub progressive: Option<usize>,
+    /// seconds after which no new pass is started
+    pub time_limit: Option<f64>,
+    /// image the progressive passes write to, the output when `None`
+    pub preview: Option<String>,
+    /// file the render state is saved to
+    pub checkpoint: Option<String>,
+    /// seconds between checkpoints
+    pub checkpoint_interval: f64,
+    /// continue from the checkpoint instead of starting over
+    pub resume: bool,
+    /// seconds into the animations the scene is rendered at
+    pub time: f64,
+    /// first and last frame of an animation rendered to numbered images, `None` renders a still
+    pub frames: Option<(usize, usize)>,
+    pub fps: f64,
+    /// seconds the shutter stays open after `time`, 0 renders without motion blur
+    pub shutter: f64,
+    /// how far the camera travels per second, it blurs the whole image while the shutter is open
+    pub camera_velocity: Vec3,
+    /// equirectangular map lighting the scene
+    pub environment: Option<String>,
+    /// degrees the environment is turned around the up axis
+    pub env_rotation: f64,
+    pub env_intensity: f64,
+    /// daylight sky and sun
+    pub sky: bool,
+    /// towards the sun
+    pub sun_dir: Vec3,
+    pub turbidity: f64,
+    pub ground_albedo: FColor,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            command: Command::Render,
+            integrator: IntegratorKind::Whitted,
+            shadow_samples: 8,
+            ao_distance: 1.,
+            ao_samples: 16,
+            aovs: Vec::new(),
+            aov_exr: false,
+            exr: false,
+            denoise: 0.,
+            adaptive_threshold: None,
+            max_spp: 64,
+            spp_heatmap: false,
+            progressive: None,
+            time_limit: None,
+            preview: None,
+            checkpoint: None,
+            checkpoint_interval: 60.,
+            resume: false,
+            time: 0.,
+            frames: None,
+            fps: 24.,
+            shutter: 0.,
+            camera_velocity: Vec3([0., 0., 0.]),
+            environment: None,
+            env_rotation: 0.,
+            env_intensity: 1.,
+            sky: false,
+            sun_dir: Vec3([0.5, 0.6, -0.6]),
+            turbidity: 3.,
+            ground_albedo: FColor::from([0.2, 0.2, 0.2]),
+        }
+    }
+}
+
+impl Settings {
+    /// Parses the arguments following the program name
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut settings = Settings::default();
+        let mut args = args.into_iter().peekable();
+
+        if let Some(command) = args.next_if(|arg| !arg.starts_with("--")) {
+            let address = args.next().ok_or(format!("missing address for {command}"));
+            settings.command = match command.as_str() {
+                "coordinator" => Command::Coordinator(address?),
+                "worker" => Command::Worker(address?),
+                "serve" => Command::Serve(address?),
+                _ => return Err(format!("unknown command {command}")),
+            };
+        }
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value for {arg}"));
+            match arg.as_str() {
+                "--integrator" => settings.integrator = value()?.parse()?,
+                "--shadow-samples" => settings.shadow_samples = parse_number(&arg, &value()?)?,
+                "--ao-distance" => settings.ao_distance = parse_number(&arg, &value()?)?,
+                "--ao-samples" => settings.ao_samples = parse_number(&arg, &value()?)?,
+                "--aov" => settings.aovs = parse_aovs(&value()?)?,
+                "--aov-exr" => settings.aov_exr = true,
+                "--exr" => settings.exr = true,
+                "--denoise" => settings.denoise = parse_number(&arg, &value()?)?,
+                "--adaptive" => settings.adaptive_threshold = Some(parse_number(&arg, &value()?)?),
+                "--max-spp" => settings.max_spp = parse_number(&arg, &value()?)?,
+                "--spp-heatmap" => settings.spp_heatmap = true,
+                "--progressive" => settings.progressive = Some(parse_number(&arg, &value()?)?),
+                "--time-limit" => settings.time_limit = Some(parse_number(&arg, &value()?)?),
+                "--preview" => settings.preview = Some(value()?),
+                "--checkpoint" => settings.checkpoint = Some(value()?),
+                "--checkpoint-interval" => settings.checkpoint_interval = parse_number(&arg, &value()?)?,
+                "--resume" => settings.resume = true,
+                "--time" => settings.time = parse_number(&arg, &value()?)?,
+                "--frames" => settings.frames = Some(parse_frames(&value()?)?),
+                "--fps" => settings.fps = parse_number(&arg, &value()?)?,
+                "--shutter" => settings.shutter = parse_number(&arg, &value()?)?,
+                "--camera-velocity" => settings.camera_velocity = Vec3(parse_vector(&arg, &value()?, false)?),
+                "--environment" => settings.environment = Some(value()?),
+                "--env-rotation" => settings.env_rotation = parse_number(&arg, &value()?)?,
+                "--env-intensity" => settings.env_intensity = parse_number(&arg, &value()?)?,
+                "--sky" => settings.sky = true,
+                "--sun-dir" => settings.sun_dir = Vec3(parse_vector(&arg, &value()?, false)?),
+                "--turbidity" => settings.turbidity = parse_number(&arg, &value()?)?,
+                "--ground-albedo" => settings.ground_albedo = FColor { rgb: parse_vector(&arg, &value()?, true)? },
+                _ => return Err(format!("unknown option {arg}")),
+            }
+        }
+
+        if settings.resume && settings.checkpoint.is_none() {
+            return Err("--resume needs the --checkpoint file".to_string());
+        }
+        let distributed = matches!(settings.command, Command::Coordinator(_) | Command::Worker(_));
+        if distributed && (settings.progressive.is_some() || settings.checkpoint.is_some()) {
+            return Err("distributed renders have no passes to show or checkpoint".to_string());
+        }
+        if settings.frames.is_some() && (distributed || settings.checkpoint.is_some()) {
+            return Err("--frames renders on this machine without checkpoints".to_string());
+        }
+        if settings.shadow_samples == 0 {
+            return Err("--shadow-samples has to be at least 1".to_string());
+        }
+        if settings.ao_samples == 0 {
+            return Err("--ao-samples has to be at least 1".to_string());
+        }
+        if settings.max_spp == 0 {
+            return Err("--max-spp has to be at least 1".to_string());
+        }
+        if settings.progressive == Some(0) {
+            return Err("--progressive passes need at least 1 spp".to_string());
+        }
+        if settings.fps <= 0. {
+            return Err("--fps has to be positive".to_string());
+        }
+        if settings.shutter < 0. {
+            return Err("--shutter can't be negative".to_string());
+        }
+        if settings.env_intensity < 0. {
+            return Err("--env-intensity can't be negative".to_string());
+        }
+        if settings.sun_dir.length() == 0. {
+            return Err("--sun-dir needs a direction".to_string());
+        }
+        if !(2. ..=10.).contains(&settings.turbidity) {
+            return Err("--turbidity ranges from 2 to 10".to_string());
+        }
+        if settings.ground_albedo.rgb.iter().any(|a| !(0. ..=1.).contains(a)) {
+            return Err("--ground-albedo ranges from 0 to 1".to_string());
+        }
+
+        Ok(settings)
+    }
+
+    /// The render mode with its options applied
+    pub fn build_integrator(&self) -> Box<dyn Integrator> {
+        match self.integrator {
+            IntegratorKind::Whitted => Box::new(Whitted {
+                shadow_samples: self.shadow_samples,
+            }),
+            IntegratorKind::Path => Box::new(PathTracer),
+            IntegratorKind::AmbientOcclusion => Box::new(AmbientOcclusion {
+                distance: self.ao_distance,
+                samples: self.ao_samples,
+            }),
+            IntegratorKind::Debug(view) => Box::new(view),
+        }
+    }
+}
+
+fn parse_aovs(list: &str) -> Result<Vec<Aov>, String> {
+    if list == "all" {
+        return Ok(Aov::ALL.to_vec());
+    }
+    list.split(',').map(|name| name.trim().parse()).collect()
+}
+
+fn parse_frames(range: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("invalid frame range {range}, e.g. 0..47");
+    let (first, last) = range.split_once("..").ok_or_else(invalid)?;
+    let (first, last) = (first.parse().map_err(|_| invalid())?, last.parse().map_err(|_| invalid())?);
+    if first > last {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+/// `x,y,z`, or a single number for all three with `splat`
+fn parse_vector(option: &str, value: &str, splat: bool) -> Result<[f64; 3], String> {
+    let numbers = value
+        .split(',')
+        .map(|x| parse_number(option, x.trim()))
+        .collect::<Result<Vec<f64>, _>>()?;
+    match numbers[..] {
+        [x, y, z] => Ok([x, y, z]),
+        [x] if splat => Ok([x; 3]),
+        _ => Err(format!("invalid value {value} for {option}, e.g. 0.5,0.6,-0.6")),
+    }
+}
+
+fn parse_number<T: FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {value} for {option}"))
+}
+
+/// What to do, rendering on this machine or together with others
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Render,
+    /// hands out tiles to the workers connecting to the address
+    Coordinator(String),
+    /// renders tiles for the coordinator at the address
+    Worker(String),
+    /// renders the jobs posted to the HTTP API at the address
+    Serve(String),
+}
+
+/// Render modes, see `Integrator`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegratorKind {
+    Whitted,
+    Path,
+    AmbientOcclusion,
+    Debug(DebugView),
+}
+
+impl FromStr for IntegratorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "whitted" => IntegratorKind::Whitted,
+            "path" => IntegratorKind::Path,
+            "ao" => IntegratorKind::AmbientOcclusion,
+            "normals" => IntegratorKind::Debug(DebugView::Normals),
+            "depth" => IntegratorKind::Debug(DebugView::Depth),
+            "albedo" => IntegratorKind::Debug(DebugView::Albedo),
+            "barycentrics" => IntegratorKind::Debug(DebugView::Barycentrics),
+            "triangles" => IntegratorKind::Debug(DebugView::TriangleIndex),
+            _ => return Err(format!("unknown integrator {s}")),
+        })
+    }
+}
@@ -0,0 +1,235 @@
+use std::f64::consts::PI;
+
+use crate::environment::EnvironmentLight;
+use crate::light_transport::{FColor, LightSample};
+use crate::maths::Vec3;
+use crate::sampling::uniform_cone;
+
+/// Angular radius of the sun seen from earth (radians)
+const SUN_ANGULAR_RADIUS: f64 = 0.00465;
+/// Illuminance of the sun above the atmosphere (klux), same unit as the sky luminance
+const SOLAR_ILLUMINANCE: f64 = 128.;
+/// Probability of sampling the sun instead of the sky dome
+const SUN_SAMPLING_PROBABILITY: f64 = 0.5;
+/// Resolution of the table the sky dome is importance sampled with
+const TABLE_SIZE: (usize, usize) = (256, 128);
+
+/// Analytic daylight: sky dome after Preetham et al., "A Practical Analytic Model for Daylight" (1999),
+/// plus the sun as a small disk. +y is up, like the environment maps.
+pub struct SkyLight {
+    dome: Dome,
+    /// irradiance of the sun disk at normal incidence, after passing the atmosphere
+    sun_irradiance: FColor,
+    /// radiance of everything below the horizon
+    ground: FColor,
+    intensity: f64,
+    /// tabulated sky dome used for picking directions
+    table: EnvironmentLight,
+}
+
+impl SkyLight {
+    /// `turbidity` ranges from 2 (clear) to 10 (hazy), `intensity` scales the luminance given in kcd/m^2
+    pub fn new(sun_direction: Vec3, turbidity: f64, ground_albedo: FColor, intensity: f64) -> Self {
+        let t = turbidity;
+        let sun_direction = sun_direction.normalize();
+        // the model breaks down below the horizon
+        let sun_theta = sun_direction.y().clamp(0.01, 1.).acos();
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        let chi = (4. / 9. - t / 120.) * (PI - 2. * sun_theta);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let theta = [sun_theta.powi(3), sun_theta.powi(2), sun_theta, 1.];
+        let dot4 = |a: [f64; 4]| a.iter().zip(theta.iter()).map(|(a, b)| a * b).sum::<f64>();
+        let zenith_x = t * t * dot4([0.00166, -0.00375, 0.00209, 0.])
+            + t * dot4([-0.02903, 0.06377, -0.03202, 0.00394])
+            + dot4([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_y = t * t * dot4([0.00275, -0.00610, 0.00317, 0.])
+            + t * dot4([-0.04214, 0.08970, -0.04153, 0.00516])
+            + dot4([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let dome = Dome {
+            sun_direction,
+            perez,
+            zenith: [f64::max(zenith_luminance, 0.), zenith_x, zenith_y],
+            sun_theta,
+        };
+        let sun_irradiance = sun_transmittance(sun_theta, t) * SOLAR_ILLUMINANCE;
+
+        // tabulate the dome, and light the ground with it and the sun
+        let (width, height) = TABLE_SIZE;
+        let texels: Vec<FColor> = (0..width * height)
+            .map(|idx| {
+                let theta = PI * ((idx / width) as f64 + 0.5) / height as f64;
+                let phi = 2. * PI * ((idx % width) as f64 + 0.5) / width as f64 - PI;
+                let dir = Vec3([theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos()]);
+                dome.radiance(dir)
+            })
+            .collect();
+
+        let texel_solid_angle = |row: usize| {
+            let theta = PI * (row as f64 + 0.5) / height as f64;
+            (2. * PI / width as f64) * (PI / height as f64) * theta.sin()
+        };
+        let sky_irradiance = texels
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| idx / width < height / 2)
+            .fold(FColor::BLACK, |sum, (idx, texel)| {
+                let theta = PI * ((idx / width) as f64 + 0.5) / height as f64;
+                sum + *texel * (theta.cos() * texel_solid_angle(idx / width))
+            });
+        let ground = (sky_irradiance + sun_irradiance * sun_theta.cos()) * ground_albedo * (1. / PI);
+
+        let texels = texels
+            .into_iter()
+            .enumerate()
+            .map(|(idx, texel)| if idx / width < height / 2 { texel } else { ground })
+            .collect();
+
+        SkyLight {
+            dome,
+            sun_irradiance,
+            ground,
+            intensity,
+            table: EnvironmentLight::from_texels(width, height, texels, 0., 1.),
+        }
+    }
+
+    /// Radiance arriving from direction `dir`, including the sun disk
+    pub fn radiance(&self, dir: Vec3) -> FColor {
+        let dir = dir.normalize();
+        if dir.y() < 0. {
+            return self.ground * self.intensity;
+        }
+
+        let mut radiance = self.dome.radiance(dir);
+        if dir.dotp(self.dome.sun_direction) >= SUN_ANGULAR_RADIUS.cos() {
+            radiance += self.sun_irradiance * (1. / sun_solid_angle());
+        }
+        radiance * self.intensity
+    }
+
+    /// Picks either a direction towards the sun disk or one on the dome, proportional to its brightness
+    pub fn sample(&self, u: [f64; 2]) -> LightSample {
+        if u[0] < SUN_SAMPLING_PROBABILITY {
+            let u = [u[0] / SUN_SAMPLING_PROBABILITY, u[1]];
+            let (cos_theta, phi) = uniform_cone(u, SUN_ANGULAR_RADIUS.cos());
+            let sin_theta = f64::max(0., 1. - cos_theta * cos_theta).sqrt();
+            let sun_direction = self.dome.sun_direction;
+            let (tangent, bitangent) = sun_direction.orthonormal_basis();
+            let dir = (sin_theta * phi.cos()) * tangent
+                + (sin_theta * phi.sin()) * bitangent
+                + cos_theta * sun_direction;
+
+            // uniform radiance over the disk divided by the uniform cone pdf leaves the irradiance
+            LightSample {
+                dir: dir.normalize(),
+                distance: f64::INFINITY,
+                radiance: self.sun_irradiance * (self.intensity / SUN_SAMPLING_PROBABILITY),
+            }
+        } else {
+            let u = [
+                (u[0] - SUN_SAMPLING_PROBABILITY) / (1. - SUN_SAMPLING_PROBABILITY),
+                u[1],
+            ];
+            let (dir, pdf) = self.table.sample_direction(u);
+            let dome = if dir.y() < 0. { self.ground } else { self.dome.radiance(dir) };
+
+            LightSample {
+                dir,
+                distance: f64::INFINITY,
+                radiance: if pdf > 0. {
+                    dome * (self.intensity / (pdf * (1. - SUN_SAMPLING_PROBABILITY)))
+                } else {
+                    FColor::BLACK
+                },
+            }
+        }
+    }
+
+    /// Solid angle pdf `sample` picks `dir` with
+    pub fn pdf(&self, dir: Vec3) -> f64 {
+        let dir = dir.normalize();
+        let sun = if dir.dotp(self.dome.sun_direction) >= SUN_ANGULAR_RADIUS.cos() {
+            1. / sun_solid_angle()
+        } else {
+            0.
+        };
+        SUN_SAMPLING_PROBABILITY * sun + (1. - SUN_SAMPLING_PROBABILITY) * self.table.pdf(dir)
+    }
+}
+
+struct Dome {
+    /// normalized direction towards the sun
+    sun_direction: Vec3,
+    /// Perez coefficients A-E for luminance Y and chromaticity x and y
+    perez: [[f64; 5]; 3],
+    /// Y, x, y at the zenith
+    zenith: [f64; 3],
+    /// sun zenith angle
+    sun_theta: f64,
+}
+
+impl Dome {
+    /// Sky dome radiance above the horizon, without the sun disk
+    fn radiance(&self, dir: Vec3) -> FColor {
+        let cos_theta = f64::max(dir.y(), 0.01);
+        let gamma = dir.dotp(self.sun_direction).clamp(-1., 1.).acos();
+
+        let [luminance, x, y] = [0, 1, 2].map(|channel| {
+            let coefficients = self.perez[channel];
+            self.zenith[channel] * perez(coefficients, cos_theta, gamma)
+                / perez(coefficients, 1., self.sun_theta)
+        });
+
+        xyy_to_rgb(x, y, luminance)
+    }
+}
+
+/// Perez et al. sky luminance distribution
+fn perez([a, b, c, d, e]: [f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    (1. + a * (b / cos_theta).exp()) * (1. + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> FColor {
+    if y <= 0. {
+        return FColor::BLACK;
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1. - x - y) / y * luminance;
+
+    // CIE XYZ to linear sRGB
+    FColor {
+        rgb: [
+            3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+            -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+            0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+        ]
+        .map(|c| f64::max(c, 0.)),
+    }
+}
+
+/// Rayleigh and aerosol extinction of sunlight, per RGB wavelength (Preetham et al., appendix A.2)
+fn sun_transmittance(sun_theta: f64, turbidity: f64) -> FColor {
+    let relative_air_mass =
+        1. / (sun_theta.cos() + 0.15 * (93.885 - sun_theta.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let alpha = 1.3;
+
+    // wavelengths in micrometers
+    let rgb = [0.680, 0.550, 0.440].map(|lambda: f64| {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = beta * lambda.powf(-alpha);
+        (-relative_air_mass * (rayleigh + aerosol)).exp()
+    });
+    FColor { rgb }
+}
+
+fn sun_solid_angle() -> f64 {
+    2. * PI * (1. - SUN_ANGULAR_RADIUS.cos())
+}
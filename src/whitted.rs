@@ -0,0 +1,154 @@
+use crate::integrator::{Integrator, LightPaths};
+use crate::intersections::{Intersect, IntersectionResult};
+use crate::light_transport::{reflect_light, Bsdf, FColor, Light, PBRMaterial};
+use crate::maths::Vec3;
+use crate::ray::Ray;
+use crate::sampling::Sampler;
+use crate::scene::Scene;
+
+const LIGHT_PATHS: usize = 2;
+
+/// Recursive mirror reflection and refraction plus direct light, `LIGHT_PATHS` deep
+pub struct Whitted {
+    /// shadow rays per area light and shading point, more give smoother penumbrae
+    pub shadow_samples: usize,
+}
+
+impl Integrator for Whitted {
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> FColor {
+        self.trace(ray, scene, sampler, 0, false).total()
+    }
+
+    fn light_paths(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Option<LightPaths> {
+        Some(self.trace(ray, scene, sampler, 0, false))
+    }
+}
+
+impl Whitted {
+    #[allow(clippy::too_many_arguments)]
+    fn shade_with(&self, mat_col: &PBRMaterial, hit_point: Vec3, hit_normal: Vec3, shading_normal: Vec3, hit_from: &Ray, scene: &Scene, sampler: &mut Sampler, light_path_num: usize) -> LightPaths {
+        if light_path_num == LIGHT_PATHS {
+            // out of bounces, the path carries no light and nothing is recorded as emission
+            return LightPaths::BLACK;
+        }
+
+        let geom = &scene.geometry;
+        // emissive surfaces are seen directly
+        let mut paths = LightPaths {
+            emission: mat_col.emissive,
+            ..LightPaths::BLACK
+        };
+
+        let view_dir = -hit_from.dir.normalize();
+        let frame = mat_col.shading_frame(hit_normal, shading_normal, view_dir);
+        let wo = frame.to_local(view_dir);
+        let bsdf = mat_col.bsdf();
+
+        // emissive surfaces as area lights
+        for _ in 0..self.shadow_samples {
+            let Some((emitter, pdf)) = scene.sample_emitter(sampler.next_f64(), hit_from.time) else {
+                break;
+            };
+            let (light_point, light_normal) = emitter.sample(sampler.next_2d());
+            let light_distance = (light_point - hit_point).length();
+            let light_dir = (light_point - hit_point).normalize();
+
+            let light_ray = Ray::spawn(hit_point, hit_normal, light_dir, light_distance * (1. - 1e-4), hit_from.time);
+
+            if light_distance > 0. && !geom.occluded(&light_ray) {
+                // area pdf to solid angle: dist^2 / cos at the emitter
+                let cos_light = light_normal.dotp(light_dir).abs();
+                let cos_surface = f64::max(0., frame.normal.dotp(light_dir));
+                let geometry_term = cos_light * cos_surface / (light_distance * light_distance);
+
+                let radiance = emitter.pbr_mat.emissive * (geometry_term / pdf / self.shadow_samples as f64);
+                add_direct(&mut paths, radiance, bsdf.as_ref(), wo, frame.to_local(light_dir));
+            }
+        }
+
+        let specular = bsdf.specular_directions(wo);
+        if specular.is_empty() {
+            // reflection, weighted by the specular reflectance towards the viewer. The highlights of the
+            // emitters and infinite lights were sampled above, the reflection only adds what they light.
+            let reflectance = mat_col.mirror_reflectance(wo.z());
+            if !reflectance.is_black() {
+                let reflected = reflect_light(hit_from, hit_normal, hit_point);
+                let reflected_ray = Ray::spawn(hit_point, hit_normal, reflected.dir, f64::INFINITY, hit_from.time);
+                let reflected = self.trace(&reflected_ray, scene, sampler, light_path_num + 1, true);
+                paths.indirect_specular += reflected.total() * reflectance;
+            }
+        } else {
+            // smooth metal and glass follow every direction they scatter into, glass both reflects and refracts
+            for (wi, weight) in specular {
+                if weight.is_black() {
+                    continue;
+                }
+                let specular_ray = Ray::spawn(hit_point, hit_normal, frame.to_world(wi), f64::INFINITY, hit_from.time);
+                paths.indirect_specular += self.trace(&specular_ray, scene, sampler, light_path_num + 1, false).total() * weight;
+            }
+        }
+
+        for light in &scene.lights {
+            let samples = if light.is_delta() { 1 } else { self.shadow_samples };
+
+            for _ in 0..samples {
+                let light_sample = light.illuminate(hit_point, sampler.next_2d());
+                let light_dir = light_sample.dir;
+
+                if light_sample.radiance.is_black() {
+                    continue;
+                }
+
+                // shadows; directional lights shoot their shadow rays to infinity
+                let light_ray = Ray::spawn(hit_point, hit_normal, light_dir, light_sample.distance * (1. - 1e-4), hit_from.time);
+                if geom.occluded(&light_ray) {
+                    continue;
+                }
+
+                let radiance = light_sample.radiance * (1. / samples as f64);
+
+                // diffuse and specular
+                let cos_surface = f64::max(0., frame.normal.dotp(light_dir));
+                add_direct(&mut paths, radiance * cos_surface, bsdf.as_ref(), wo, frame.to_local(light_dir));
+            }
+        }
+
+        paths
+        //c_shaded.into()
+    }
+
+    /// Radiance arriving along `ray`, from the surface it hits or the background.
+    /// With `emitters_sampled` the light of emissive surfaces and infinite lights is left out,
+    /// the vertex the ray leaves from sampled it already.
+    fn trace(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler, light_path_num: usize, emitters_sampled: bool) -> LightPaths {
+        if let IntersectionResult::Hit {
+            material,
+            normal,
+            shading_normal,
+            point,
+            t,
+            ..
+        } = scene.geometry.intersect(ray) {
+            let mut paths = self.shade_with(material, point, normal, shading_normal, ray, scene, sampler, light_path_num);
+            if emitters_sampled {
+                paths.emission = FColor::BLACK;
+            }
+            paths.scale(material.transmittance_to(ray.dir, normal, t * ray.dir.length()))
+        } else if emitters_sampled && scene.lights.iter().any(Light::is_infinite) {
+            LightPaths::BLACK
+        } else {
+            LightPaths {
+                emission: scene.background(ray.dir),
+                ..LightPaths::BLACK
+            }
+        }
+    }
+}
+
+/// Adds light arriving from `wi`, split into the diffuse and specular part the BSDF reflects towards `wo`
+fn add_direct(paths: &mut LightPaths, radiance: FColor, bsdf: &dyn Bsdf, wo: Vec3, wi: Vec3) {
+    let f = bsdf.eval(wo, wi);
+    let f_diffuse = bsdf.eval_diffuse(wo, wi);
+    paths.direct_diffuse += radiance * f_diffuse;
+    paths.direct_specular += radiance * (f - f_diffuse);
+}